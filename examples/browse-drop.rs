@@ -1,7 +1,6 @@
-use astro_dnssd::{BrowseError, ServiceBrowserBuilder, ServiceEventType};
+use astro_dnssd::{ServiceBrowserBuilder, ServiceEventType};
 use env_logger::Env;
 use log::{error, info};
-use std::io::ErrorKind;
 use std::time::{Duration, Instant};
 
 fn main() {
@@ -15,22 +14,23 @@ fn main() {
                 info!("Browser started!");
                 loop {
                     match browser.recv_timeout(Duration::from_millis(500)) {
-                        Ok(service) => {
-                            if service.event_type == ServiceEventType::Added {
-                                info!("Service found: {:?}", service);
-                            } else {
-                                info!("Service left: {}", service.hostname);
+                        Ok(Some(service)) => match &service.event_type {
+                            ServiceEventType::Added => info!("Service found: {:?}", service),
+                            ServiceEventType::Removed => {
+                                info!("Service left: {}", service.hostname)
                             }
-                        }
-                        Err(BrowseError::IoError(e)) if e.kind() == ErrorKind::TimedOut => {
-                            std::thread::sleep(Duration::from_millis(100));
-                        }
-                        Err(BrowseError::Timeout) => {
+                            ServiceEventType::TxtUpdated => {
+                                info!(
+                                    "Service {} updated TXT record: {:?}",
+                                    service.name, service.txt_record
+                                )
+                            }
+                        },
+                        Ok(None) => {
                             if start.elapsed() > Duration::from_secs(5) {
                                 info!("Exiting browser loop to test drop");
                                 break;
                             }
-                            std::thread::sleep(Duration::from_millis(100));
                         }
                         Err(e) => {
                             error!("Error receiving browser service: {:?}", e);
@@ -14,15 +14,23 @@ fn main() {
     {
         match service {
             Ok(service) => {
-                println!("Registered... waiting 20s");
+                println!(
+                    "Registered {} as {} in {}... waiting 20s",
+                    service.name(),
+                    service.regtype(),
+                    service.domain()
+                );
                 sleep(Duration::from_secs(20));
-                println!("Dropping... {:?}", service);
+                println!("Unregistering... {:?}", service);
+                if let Err(e) = service.unregister() {
+                    println!("Error unregistering: {:?}", e);
+                }
             }
             Err(e) => {
                 println!("Error registering: {:?}", e);
             }
         }
     }
-    log::info!("Drop should have happened");
+    log::info!("Unregister should have happened");
     sleep(Duration::from_secs(5));
 }
@@ -0,0 +1,30 @@
+use astro_dnssd::DNSServiceBuilder;
+use env_logger::Env;
+use std::time::Duration;
+
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    async_io::block_on(async {
+        println!("Registering service...");
+        let service = DNSServiceBuilder::new("_http._tcp", 8080)
+            .with_key_value("status".into(), "open".into())
+            .with_timeout(Duration::from_secs(5))
+            .register_async()
+            .await;
+        match service {
+            Ok(service) => {
+                println!(
+                    "Registered {} as {} in {}... waiting 20s",
+                    service.name(),
+                    service.regtype(),
+                    service.domain()
+                );
+                async_io::Timer::after(Duration::from_secs(20)).await;
+                println!("Unregistering... {:?}", service);
+            }
+            Err(e) => {
+                println!("Error registering: {:?}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,46 @@
+use astro_dnssd::{DNSServiceBuilder, Runtime, ServiceBrowserBuilder};
+use env_logger::Env;
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let runtime = Runtime::new().expect("Failed to start runtime");
+    // every registration & browser shares the runtime's connection & thread
+    let services: Vec<_> = (1..=3)
+        .map(|n| {
+            DNSServiceBuilder::new("_http._tcp", 8080 + n)
+                .with_name(&format!("Runtime example {}", n))
+                .with_runtime(&runtime)
+                .register()
+        })
+        .collect();
+    for service in &services {
+        match service {
+            Ok(service) => info!("Registered {}", service.name()),
+            Err(e) => error!("Error registering: {:?}", e),
+        }
+    }
+    let browser = match ServiceBrowserBuilder::new("_http._tcp")
+        .with_runtime(&runtime)
+        .browse()
+    {
+        Ok(browser) => browser,
+        Err(e) => {
+            error!("Error starting browser: {:?}", e);
+            return;
+        }
+    };
+    let until = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < until {
+        match browser.recv_timeout(Duration::from_millis(500)) {
+            Ok(Some(service)) => info!(
+                "{:?}: {} on port {}",
+                service.event_type, service.name, service.port
+            ),
+            Ok(None) => {}
+            Err(e) => error!("Error receiving browser service: {:?}", e),
+        }
+    }
+    info!("Runtime status: {:?}", runtime.status());
+}
@@ -0,0 +1,32 @@
+use astro_dnssd::{ServiceBrowserBuilder, ServiceEventType};
+use env_logger::Env;
+use futures_util::StreamExt;
+use log::{error, info};
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let mut services = match ServiceBrowserBuilder::new("_http._tcp").browse() {
+        Ok(browser) => browser.into_stream(),
+        Err(e) => {
+            error!("Error starting browser: {:?}", e);
+            return;
+        }
+    };
+    info!("Browser started!");
+    while let Some(service) = services.next().await {
+        match service {
+            Ok(service) => match &service.event_type {
+                ServiceEventType::Added => info!("Service found: {:?}", service),
+                ServiceEventType::Removed => info!("Service left: {}", service.name),
+                ServiceEventType::TxtUpdated => {
+                    info!(
+                        "Service {} updated TXT record: {:?}",
+                        service.name, service.txt_record
+                    )
+                }
+            },
+            Err(e) => error!("Error receiving browser service: {:?}", e),
+        }
+    }
+}
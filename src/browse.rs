@@ -1,20 +1,24 @@
-pub use crate::os::{BrowseError, ServiceBrowser};
-use std::collections::HashMap;
+use crate::error::BrowseError;
+use crate::full_name::{FullNameError, ServiceFullName};
+use crate::interface::Interface;
+pub use crate::os::ServiceBrowser;
+use crate::os::{resolve, Runtime};
+use crate::service_type::RegType;
+use crate::txt::TXTRecord;
+use std::time::Duration;
 
 /// Service browsing result type
 pub type Result<T, E = BrowseError> = std::result::Result<T, E>;
 
-pub trait ServiceBrowserTrait {
-    fn next_entry(&self) -> Result<Service>;
-}
-
-/// Type of service event from browser, if a service is being added or removed from network
+/// Type of service event from browser, if a service is being added, removed or updated on the network
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ServiceEventType {
     /// Service has been added to the network
     Added,
     /// Service is removed from the network
     Removed,
+    /// Service already found has changed its TXT record, the new one is in `txt_record`
+    TxtUpdated,
 }
 
 /// Encapsulates information about a service
@@ -24,8 +28,10 @@ pub struct Service {
     pub name: String,
     /// Registration type, i.e. _http._tcp.
     pub regtype: String,
-    /// Interface index (unsure what this is for)
+    /// Index of the interface service was found on
     pub interface_index: Option<u32>,
+    /// OS name of the interface service was found on (i.e. `eth0`), if known
+    pub interface_name: Option<String>,
     /// Domain service is on, typically local.
     pub domain: String,
     /// Whether this service is being added or not
@@ -37,30 +43,220 @@ pub struct Service {
     /// Port service is on
     pub port: u16,
     /// TXT record service has if any
-    pub txt_record: Option<HashMap<String, String>>,
+    pub txt_record: Option<TXTRecord>,
+}
+impl Service {
+    /// Full name of the service, escaped for dns_sd when displayed
+    pub fn full_name(&self) -> Result<ServiceFullName, FullNameError> {
+        ServiceFullName::new(&self.name, &self.regtype.parse()?, &self.domain)
+    }
+    /// Service as discovered, to resolve it again, TXT updates are discovered as added
+    pub fn discovered(&self) -> DiscoveredService {
+        DiscoveredService {
+            name: self.name.clone(),
+            regtype: self.regtype.clone(),
+            interface_index: self.interface_index.unwrap_or(0),
+            domain: self.domain.clone(),
+            event_type: match self.event_type {
+                ServiceEventType::TxtUpdated => ServiceEventType::Added,
+                event_type => event_type,
+            },
+        }
+    }
+}
+
+/// Service found by a browser before it's resolved, to resolve on demand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredService {
+    /// Name of service, usually a user friendly name
+    pub name: String,
+    /// Registration type, i.e. _http._tcp.
+    pub regtype: String,
+    /// Index of the interface service was found on, 0 for any
+    pub interface_index: u32,
+    /// Domain service is on, typically local.
+    pub domain: String,
+    /// Whether this service is being added or not
+    pub event_type: ServiceEventType,
+}
+impl DiscoveredService {
+    /// Resolves the service's host, port & TXT record, blocking until it's resolved
+    pub fn resolve(&self) -> Result<Service> {
+        // without a timeout it only returns once resolved or stopped
+        resolve(self, None)?.ok_or(BrowseError::Stopped)
+    }
+    /// Resolves the service's host, port & TXT record, none if it isn't resolved by the timeout
+    pub fn resolve_timeout(&self, timeout: Duration) -> Result<Option<Service>> {
+        resolve(self, Some(timeout))
+    }
+}
+
+impl ServiceBrowser {
+    /// Blocks until a service is found, failing with `Error::Stopped` once the browser's stopped
+    ///
+    /// Fails with `Error::BrowseOnly` if browsing with `with_no_resolve`.
+    pub fn recv(&self) -> Result<Service> {
+        if self.browse_only() {
+            return Err(BrowseError::BrowseOnly);
+        }
+        self.next_service().unwrap_or(Err(BrowseError::Stopped))
+    }
+    /// Returns a service if one was found, without waiting, none if there wasn't one yet
+    pub fn try_recv(&self) -> Result<Option<Service>> {
+        self.recv_timeout(Duration::ZERO)
+    }
+    /// Blocks until a service is found, unresolved if browsing with `with_no_resolve`, failing
+    /// with `Error::Stopped` once the browser's stopped
+    pub fn recv_discovered(&self) -> Result<DiscoveredService> {
+        // without a timeout it only returns once there's a service or the browser's stopped
+        self.next_discovered(None)?.ok_or(BrowseError::Stopped)
+    }
+    /// Returns a service if one was found by the timeout, unresolved if browsing with
+    /// `with_no_resolve`, none if there wasn't one yet
+    pub fn recv_discovered_timeout(&self, timeout: Duration) -> Result<Option<DiscoveredService>> {
+        self.next_discovered(Some(timeout))
+    }
+    /// Iterates over services as they're found, blocking until each is, ending once the
+    /// browser's stopped
+    ///
+    /// If browsing with `with_no_resolve` it fails with `Error::BrowseOnly` once, then ends.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { browser: self }
+    }
+}
+
+/// Iterator over services found by a borrowed browser, from `ServiceBrowser::iter`
+pub struct Iter<'a> {
+    browser: &'a ServiceBrowser,
+}
+impl Iterator for Iter<'_> {
+    type Item = Result<Service>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.browser.next_service()
+    }
+}
+impl<'a> IntoIterator for &'a ServiceBrowser {
+    type Item = Result<Service>;
+    type IntoIter = Iter<'a>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over services found by a browser it owns, browsing until the iterator's dropped
+pub struct IntoIter {
+    browser: ServiceBrowser,
+}
+impl Iterator for IntoIter {
+    type Item = Result<Service>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.browser.next_service()
+    }
+}
+impl IntoIterator for ServiceBrowser {
+    type Item = Result<Service>;
+    type IntoIter = IntoIter;
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter { browser: self }
+    }
 }
 
 /// Builder for creating a browser, allowing optionally specifying a domain with chaining (maybe builder is excessive)
 pub struct ServiceBrowserBuilder {
+    service_type: RegType,
+    /// Service type without a subtype, set from `service_type` when browsing starts
     pub(crate) regtype: String,
     pub(crate) domain: Option<String>,
+    pub(crate) subtype: Option<String>,
+    pub(crate) interface: Option<Interface>,
+    pub(crate) runtime: Option<Runtime>,
+    pub(crate) manual_processing: bool,
+    pub(crate) no_resolve: bool,
 }
 
 impl ServiceBrowserBuilder {
-    /// Creates new service browser for given service type, i.e. ._http._tcp
-    pub fn new(regtype: &str) -> ServiceBrowserBuilder {
+    /// Creates new service browser for given service type, i.e. ._http._tcp, as a string or
+    /// `ServiceType`
+    ///
+    /// A string is validated when browsing starts, failing with `InvalidServiceType` if it isn't
+    /// a valid service type or the `_services._dns-sd._udp` meta-query. It may have a single
+    /// subtype in place of `with_subtype`.
+    pub fn new<T: Into<RegType>>(regtype: T) -> ServiceBrowserBuilder {
         ServiceBrowserBuilder {
-            regtype: String::from(regtype),
+            service_type: regtype.into(),
+            regtype: String::new(),
             domain: None,
+            subtype: None,
+            interface: None,
+            runtime: None,
+            manual_processing: false,
+            no_resolve: false,
         }
     }
+    /// Creates new service browser for a type browsed as is, without validating it
+    ///
+    /// For legacy types RFC 6335 doesn't allow, i.e. with underscores like `_my_type._tcp`.
+    pub fn new_unchecked(regtype: &str) -> ServiceBrowserBuilder {
+        ServiceBrowserBuilder::new(RegType::unchecked(regtype))
+    }
     /// Adds a specified domain to browser's search
     pub fn with_domain(mut self, domain: &str) -> ServiceBrowserBuilder {
         self.domain = Some(String::from(domain));
         self
     }
+    /// Only browses on the given interface, by index or OS name (i.e. `eth0`)
+    ///
+    /// Defaults to all interfaces, names are resolved when browsing starts.
+    pub fn with_interface<I: Into<Interface>>(mut self, interface: I) -> ServiceBrowserBuilder {
+        self.interface = Some(interface.into());
+        self
+    }
+    /// Only finds services advertised under a subtype (i.e. `_printer`), replacing any previous one
+    ///
+    /// The subtype is validated when browsing starts, failing if not a valid label.
+    pub fn with_subtype(mut self, subtype: &str) -> ServiceBrowserBuilder {
+        self.subtype = Some(String::from(subtype));
+        self
+    }
+    /// Browses & resolves on a shared runtime instead of a thread for this browser
+    ///
+    /// Avahi's dns_sd compat layer on Linux can't share a connection, so a runtime can't be
+    /// created there.
+    pub fn with_runtime(mut self, runtime: &Runtime) -> ServiceBrowserBuilder {
+        self.runtime = Some(runtime.clone());
+        self
+    }
+    /// Browses & resolves only when `process_ready` is called instead of on a thread, for event
+    /// loops watching the browser's `sockets`
+    ///
+    /// Ignored with `with_runtime`, & by the Windows DNS API which calls back on its own threads.
+    /// Each resolve has a socket of its own alongside the browse's, so they change as services
+    /// are found.
+    pub fn with_manual_processing(mut self) -> ServiceBrowserBuilder {
+        self.manual_processing = true;
+        self
+    }
+    /// Delivers services as soon as they're found instead of resolving each first
+    ///
+    /// Receive them with `ServiceBrowser::recv_discovered` & resolve the ones needed with
+    /// `DiscoveredService::resolve`, the `Service` receivers fail with `Error::BrowseOnly`. The
+    /// Windows DNS API finds services already resolved, they're delivered the same way.
+    pub fn with_no_resolve(mut self) -> ServiceBrowserBuilder {
+        self.no_resolve = true;
+        self
+    }
     /// Starts the browser
-    pub fn browse(self) -> Result<ServiceBrowser> {
+    pub fn browse(mut self) -> Result<ServiceBrowser> {
+        let (regtype, subtypes) = self.service_type.split()?;
+        let mut subtypes = subtypes.into_iter().chain(self.subtype);
+        self.subtype = subtypes.next();
+        if subtypes.next().is_some() {
+            return Err(BrowseError::InvalidSubtype(format!(
+                "{} has more than the one subtype a browser can take",
+                self.service_type
+            )));
+        }
+        self.regtype = regtype;
         crate::os::browse(self)
     }
 }
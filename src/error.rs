@@ -0,0 +1,303 @@
+//! Error shared by every backend, so errors can be matched the same way on any platform
+
+use crate::full_name::FullNameError;
+use crate::record::RecordError;
+use crate::service_type::ServiceTypeError;
+use crate::txt::TXTError;
+use std::io::Error as IoError;
+use thiserror::Error;
+
+/// Error while browsing for or registering DNS-SD services
+///
+/// Each condition has one variant, `service_error_kind` decodes the ones the daemon also reports.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Timeout waiting for the daemon to reply, or the daemon timing out
+    ///
+    /// Reported as `ServiceErrorKind::Timeout` by `service_error_kind`.
+    #[error("Timeout waiting for data")]
+    Timeout,
+    /// IO error
+    #[error("IO Error: {0}")]
+    IoError(#[from] IoError),
+    /// Error code returned by the DNS-SD daemon or Windows DNS API, other than timeouts &
+    /// unsupported operations which have their own variants
+    #[error("DNS-SD Error: {code} ({kind:?})")]
+    ServiceError {
+        /// Decoded error, `Unknown` for codes without a named kind
+        kind: ServiceErrorKind,
+        /// Raw code, a `kDNSServiceErr_*` value or a Windows DNS status
+        code: i32,
+    },
+    /// Given string isn't C string compatible, i.e. has a nul byte
+    #[error("Invalid {field}, must be C string compatible")]
+    InvalidString {
+        /// Argument that couldn't be converted, i.e. `name` or `domain`
+        field: &'static str,
+    },
+    /// DNS-SD API returned a string that isn't valid UTF-8
+    #[error("DNS-SD API returned an invalid UTF-8 {field}")]
+    InternalInvalidString {
+        /// Reply field that couldn't be converted, i.e. `hostname`
+        field: &'static str,
+    },
+    /// TXT record data couldn't be encoded
+    #[error("Invalid TXT record: {0}")]
+    InvalidTxtRecord(#[from] TXTError),
+    /// Subtype isn't a valid label
+    #[error("Invalid subtype: {0}")]
+    InvalidSubtype(String),
+    /// Service type isn't valid, i.e. `_http.tcp` or a service name over 15 characters
+    #[error("Invalid service type: {0}")]
+    InvalidServiceType(#[from] ServiceTypeError),
+    /// Full name of a found service couldn't be parsed
+    #[error("Invalid full name: {0}")]
+    InvalidFullName(#[from] FullNameError),
+    /// No network interface by the given name
+    #[error("Unknown interface: {0}")]
+    UnknownInterface(String),
+    /// Host addresses were given without a host to register them for
+    #[error("Host addresses given without a host")]
+    MissingHost,
+    /// Additional record data couldn't be encoded
+    #[error("Invalid record: {0}")]
+    InvalidRecord(#[from] RecordError),
+    /// Record was already removed
+    #[error("Record was already removed")]
+    RecordRemoved,
+    /// Browser stopped, so there are no more services to receive
+    #[error("Browser was stopped")]
+    Stopped,
+    /// Browser only finds services unresolved, with `with_no_resolve`
+    #[error("Browser doesn't resolve services, receive them with recv_discovered")]
+    BrowseOnly,
+    /// The DNS-SD backend doesn't support the operation, i.e. Avahi's compat layer on Linux or
+    /// subtypes & records with the Windows DNS API
+    ///
+    /// Reported as `ServiceErrorKind::Unsupported` by `service_error_kind`.
+    #[error("{0} isn't supported by this DNS-SD backend")]
+    Unsupported(&'static str),
+}
+
+/// Error while browsing for DNS-SD services
+pub type BrowseError = Error;
+/// Error during DNS-SD registration
+pub type RegistrationError = Error;
+
+/// Decoded error code from the DNS-SD daemon, named after the `kDNSServiceErr_*` constants
+///
+/// Windows DNS statuses are decoded to the nearest kind where there's one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceErrorKind {
+    /// Code without a named kind
+    Unknown,
+    /// Name doesn't exist
+    NoSuchName,
+    /// Daemon ran out of memory
+    NoMemory,
+    /// Invalid argument
+    BadParam,
+    /// Invalid or already deallocated service ref
+    BadReference,
+    /// Operation isn't valid in the current state
+    BadState,
+    /// Invalid flags
+    BadFlags,
+    /// Operation isn't supported
+    Unsupported,
+    /// Ref wasn't initialized
+    NotInitialized,
+    /// Already registered
+    AlreadyRegistered,
+    /// Name is already taken, by another service or host, with auto renaming disabled or
+    /// renames exhausted
+    NameConflict,
+    /// Invalid data
+    Invalid,
+    /// Blocked by a firewall
+    Firewall,
+    /// Client library isn't compatible with the daemon
+    Incompatible,
+    /// Interface index doesn't exist
+    BadInterfaceIndex,
+    /// Request was refused
+    Refused,
+    /// No such record
+    NoSuchRecord,
+    /// Not authorized
+    NoAuth,
+    /// Key doesn't exist
+    NoSuchKey,
+    /// NAT traversal failed
+    NATTraversal,
+    /// Behind two layers of NAT
+    DoubleNAT,
+    /// Clock is off
+    BadTime,
+    /// Bad signature
+    BadSig,
+    /// Bad key
+    BadKey,
+    /// Temporary failure, may succeed when retried
+    Transient,
+    /// Daemon isn't running, or the connection to it was lost
+    ServiceNotRunning,
+    /// NAT port mapping isn't supported by the router
+    NATPortMappingUnsupported,
+    /// NAT port mapping is disabled on the router
+    NATPortMappingDisabled,
+    /// No router for NAT port mapping
+    NoRouter,
+    /// Polling mode
+    PollingMode,
+    /// Daemon timed out
+    Timeout,
+    /// Connection to the daemon was closed by it
+    DefunctConnection,
+}
+
+impl ServiceErrorKind {
+    /// Decodes a `kDNSServiceErr_*` code
+    pub fn from_dnssd(code: i32) -> Self {
+        use ServiceErrorKind::*;
+        match code {
+            -65538 => NoSuchName,
+            -65539 => NoMemory,
+            -65540 => BadParam,
+            -65541 => BadReference,
+            -65542 => BadState,
+            -65543 => BadFlags,
+            -65544 => Unsupported,
+            -65545 => NotInitialized,
+            -65547 => AlreadyRegistered,
+            -65548 => NameConflict,
+            -65549 => Invalid,
+            -65550 => Firewall,
+            -65551 => Incompatible,
+            -65552 => BadInterfaceIndex,
+            -65553 => Refused,
+            -65554 => NoSuchRecord,
+            -65555 => NoAuth,
+            -65556 => NoSuchKey,
+            -65557 => NATTraversal,
+            -65558 => DoubleNAT,
+            -65559 => BadTime,
+            -65560 => BadSig,
+            -65561 => BadKey,
+            -65562 => Transient,
+            -65563 => ServiceNotRunning,
+            -65564 => NATPortMappingUnsupported,
+            -65565 => NATPortMappingDisabled,
+            -65566 => NoRouter,
+            -65567 => PollingMode,
+            -65568 => Timeout,
+            -65569 => DefunctConnection,
+            _ => Unknown,
+        }
+    }
+    /// Decodes a Windows DNS API status, i.e. `DNS_ERROR_RCODE_NAME_ERROR`
+    pub fn from_windows(status: u32) -> Self {
+        use ServiceErrorKind::*;
+        match status {
+            // DNS_ERROR_RCODE_YXDOMAIN, name in use
+            9006 => NameConflict,
+            8 | 14 => NoMemory,
+            50 => Unsupported,
+            87 => BadParam,
+            1062 => ServiceNotRunning,
+            1460 => Timeout,
+            9003 => NoSuchName,
+            9005 => Refused,
+            9501 => NoSuchRecord,
+            _ => Unknown,
+        }
+    }
+}
+
+impl Error {
+    /// Error for a decoded code, with the variant of its kind if it has one
+    fn from_code(kind: ServiceErrorKind, code: i32) -> Self {
+        match kind {
+            ServiceErrorKind::Timeout => Error::Timeout,
+            ServiceErrorKind::Unsupported => Error::Unsupported("The operation"),
+            kind => Error::ServiceError { kind, code },
+        }
+    }
+    /// Error for a `kDNSServiceErr_*` code
+    #[cfg(any(feature = "win-bonjour", not(target_os = "windows")))]
+    pub(crate) fn service(code: i32) -> Self {
+        Error::from_code(ServiceErrorKind::from_dnssd(code), code)
+    }
+    /// Error for a `kDNSServiceErr_*` code from an operation not every DNS-SD library supports,
+    /// naming the operation if it's the one unsupported
+    #[cfg(any(feature = "win-bonjour", not(target_os = "windows")))]
+    pub(crate) fn service_or_unsupported(code: i32, operation: &'static str) -> Self {
+        match ServiceErrorKind::from_dnssd(code) {
+            ServiceErrorKind::Unsupported => Error::Unsupported(operation),
+            _ => Error::service(code),
+        }
+    }
+    /// Error for a Windows DNS API status
+    #[cfg(all(not(feature = "win-bonjour"), target_os = "windows"))]
+    pub(crate) fn dns_status(status: u32) -> Self {
+        Error::from_code(ServiceErrorKind::from_windows(status), status as i32)
+    }
+    /// Decoded kind of a daemon error code, if this is one or a condition the daemon also
+    /// reports, i.e. timing out
+    pub fn service_error_kind(&self) -> Option<ServiceErrorKind> {
+        match self {
+            Error::ServiceError { kind, .. } => Some(*kind),
+            Error::Timeout => Some(ServiceErrorKind::Timeout),
+            Error::Unsupported(_operation) => Some(ServiceErrorKind::Unsupported),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_error_codes() {
+        assert_eq!(
+            ServiceErrorKind::from_dnssd(-65548),
+            ServiceErrorKind::NameConflict
+        );
+        assert_eq!(
+            ServiceErrorKind::from_dnssd(-65563),
+            ServiceErrorKind::ServiceNotRunning
+        );
+        assert_eq!(ServiceErrorKind::from_dnssd(-1), ServiceErrorKind::Unknown);
+        assert_eq!(
+            ServiceErrorKind::from_windows(9006),
+            ServiceErrorKind::NameConflict
+        );
+        assert_eq!(
+            ServiceErrorKind::from_windows(1460),
+            ServiceErrorKind::Timeout
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "win-bonjour", not(target_os = "windows")))]
+    fn unsupported_operations() {
+        let e = Error::service_or_unsupported(-65544, "Registering host records");
+        assert_eq!(e.service_error_kind(), Some(ServiceErrorKind::Unsupported));
+        assert_eq!(
+            e.to_string(),
+            "Registering host records isn't supported by this DNS-SD backend"
+        );
+        let e = Error::service(-65568);
+        assert!(matches!(e, Error::Timeout));
+        assert_eq!(e.service_error_kind(), Some(ServiceErrorKind::Timeout));
+        let e = Error::service(-65548);
+        assert_eq!(e.service_error_kind(), Some(ServiceErrorKind::NameConflict));
+        let e = Error::service_or_unsupported(-65563, "Registering host records");
+        assert_eq!(
+            e.service_error_kind(),
+            Some(ServiceErrorKind::ServiceNotRunning)
+        );
+    }
+}
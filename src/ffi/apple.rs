@@ -466,8 +466,10 @@ fn bindgen_test_layout__TXTRecordRef_t() {
         8usize,
         concat!("Alignment of ", stringify!(_TXTRecordRef_t))
     );
+    const UNINIT: ::std::mem::MaybeUninit<_TXTRecordRef_t> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
     assert_eq!(
-        unsafe { &(*(::std::ptr::null::<_TXTRecordRef_t>())).PrivateData as *const _ as usize },
+        unsafe { ::std::ptr::addr_of!((*ptr).PrivateData) as usize - ptr as usize },
         0usize,
         concat!(
             "Offset of field: ",
@@ -477,9 +479,7 @@ fn bindgen_test_layout__TXTRecordRef_t() {
         )
     );
     assert_eq!(
-        unsafe {
-            &(*(::std::ptr::null::<_TXTRecordRef_t>())).ForceNaturalAlignment as *const _ as usize
-        },
+        unsafe { ::std::ptr::addr_of!((*ptr).ForceNaturalAlignment) as usize - ptr as usize },
         0usize,
         concat!(
             "Offset of field: ",
@@ -0,0 +1,203 @@
+//! Full service names, i.e. `My\032Printer._ipp._tcp.local.`, escaped as dns_sd does
+
+use crate::service_type::{ServiceType, ServiceTypeError};
+use crate::subtype::MAX_LABEL_LEN;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Error parsing or building a full service name
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum FullNameError {
+    /// Name isn't in the form `instance._service._tcp.domain.`, or has an invalid escape
+    #[error("Full name {0} isn't in the form instance._service._tcp.domain.")]
+    Malformed(String),
+    /// Instance or domain label is empty or over 63 bytes
+    #[error("Label {0} is empty or over 63 bytes")]
+    InvalidLabel(String),
+    /// Service type isn't valid
+    #[error("Invalid service type: {0}")]
+    InvalidServiceType(#[from] ServiceTypeError),
+}
+
+/// Escapes a label as `DNSServiceConstructFullName` does, with dots & backslashes preceded by a
+/// backslash & other ASCII control characters or spaces as `\DDD`
+fn escape_label(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '.' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c <= ' ' || c == '\x7f' => escaped.push_str(&format!("\\{:03}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits an escaped name into its unescaped labels, ignoring a trailing dot
+fn unescape_labels(name: &str) -> Result<Vec<String>, FullNameError> {
+    let malformed = || FullNameError::Malformed(name.to_string());
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut bytes = name.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => match bytes.next().ok_or_else(malformed)? {
+                digit @ b'0'..=b'9' => {
+                    let digits = [
+                        digit,
+                        bytes.next().ok_or_else(malformed)?,
+                        bytes.next().ok_or_else(malformed)?,
+                    ];
+                    let value = std::str::from_utf8(&digits)
+                        .ok()
+                        .and_then(|digits| digits.parse::<u8>().ok())
+                        .ok_or_else(malformed)?;
+                    label.push(value);
+                }
+                escaped => label.push(escaped),
+            },
+            b'.' => labels.push(std::mem::take(&mut label)),
+            byte => label.push(byte),
+        }
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    labels
+        .into_iter()
+        .map(|label| String::from_utf8(label).map_err(|_| malformed()))
+        .collect()
+}
+
+/// Validates an unescaped instance or domain label
+fn validate_label(label: &str) -> Result<(), FullNameError> {
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return Err(FullNameError::InvalidLabel(label.to_string()));
+    }
+    Ok(())
+}
+
+/// Full name of a service instance, i.e. `My Printer` of type `_ipp._tcp` in `local.`
+///
+/// Displayed escaped as dns_sd expects, i.e. `My\032Printer._ipp._tcp.local.`, & parsed back
+/// from that form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServiceFullName {
+    instance: String,
+    service_type: ServiceType,
+    /// Escaped, with a trailing dot
+    domain: String,
+}
+
+impl ServiceFullName {
+    /// Creates a full name from an unescaped instance name, service type (without subtypes) &
+    /// domain, i.e. `local.`
+    pub fn new(
+        instance: &str,
+        service_type: &ServiceType,
+        domain: &str,
+    ) -> Result<Self, FullNameError> {
+        validate_label(instance)?;
+        let domain = unescape_labels(domain)?;
+        domain.iter().try_for_each(|label| validate_label(label))?;
+        if domain.is_empty() {
+            return Err(FullNameError::InvalidLabel(String::new()));
+        }
+        let domain: Vec<String> = domain.iter().map(|label| escape_label(label)).collect();
+        Ok(ServiceFullName {
+            instance: instance.to_string(),
+            service_type: ServiceType::new(service_type.name(), service_type.protocol())?,
+            domain: format!("{}.", domain.join(".")),
+        })
+    }
+    /// Unescaped instance name, i.e. `My Printer`
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+    /// Service type, i.e. `_ipp._tcp`
+    pub fn service_type(&self) -> &ServiceType {
+        &self.service_type
+    }
+    /// Domain with a trailing dot, i.e. `local.`
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+}
+
+impl FromStr for ServiceFullName {
+    type Err = FullNameError;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let labels = unescape_labels(name)?;
+        match labels.as_slice() {
+            [instance, service, protocol, domain @ ..] if !domain.is_empty() => {
+                let service_type: ServiceType = format!("{}.{}", service, protocol).parse()?;
+                let domain: Vec<String> = domain.iter().map(|label| escape_label(label)).collect();
+                ServiceFullName::new(instance, &service_type, &domain.join("."))
+            }
+            _ => Err(FullNameError::Malformed(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ServiceFullName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            escape_label(&self.instance),
+            self.service_type.regtype(),
+            self.domain
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_names() {
+        let ipp: ServiceType = "_ipp._tcp".parse().unwrap();
+        let printer = ServiceFullName::new("My Printer", &ipp, "local").unwrap();
+        assert_eq!(printer.to_string(), "My\\032Printer._ipp._tcp.local.");
+        assert_eq!("My\\032Printer._ipp._tcp.local.".parse(), Ok(printer));
+
+        let dotted = ServiceFullName::new("Büro v1.2 \\o/", &ipp, "local.").unwrap();
+        assert_eq!(
+            dotted.to_string(),
+            "Büro\\032v1\\.2\\032\\\\o/._ipp._tcp.local."
+        );
+        let parsed: ServiceFullName = dotted.to_string().parse().unwrap();
+        assert_eq!(parsed.instance(), "Büro v1.2 \\o/");
+        assert_eq!(parsed.domain(), "local.");
+        assert_eq!(parsed.service_type(), &ipp);
+
+        let windows: ServiceFullName = "Office._http._tcp.local".parse().unwrap();
+        assert_eq!(windows.instance(), "Office");
+        assert_eq!(windows.domain(), "local.");
+    }
+
+    #[test]
+    fn invalid_full_names() {
+        let http: ServiceType = "_http._tcp".parse().unwrap();
+        assert_eq!(
+            ServiceFullName::new(&"a".repeat(64), &http, "local."),
+            Err(FullNameError::InvalidLabel("a".repeat(64)))
+        );
+        assert!(ServiceFullName::new(&"a".repeat(63), &http, "local.").is_ok());
+        assert!(ServiceFullName::new("", &http, "local.").is_err());
+        assert!("_http._tcp.local.".parse::<ServiceFullName>().is_err());
+        assert!("a\\99".parse::<ServiceFullName>().is_err());
+        assert!("a\\999._http._tcp.local."
+            .parse::<ServiceFullName>()
+            .is_err());
+        assert!(matches!(
+            "a._http.tcp.local.".parse::<ServiceFullName>(),
+            Err(FullNameError::InvalidServiceType(_))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+//! Network interfaces services are registered or browsed on
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+/// Big enough for interface names on all platforms (IF_NAMESIZE is 16 on unix, 257 on Windows)
+const NAME_BUFFER_LEN: usize = 257;
+
+/// Network interface to limit registration or browsing to, by index or OS name (i.e. `eth0`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Interface {
+    /// Interface index, 0 meaning all interfaces
+    Index(u32),
+    /// Interface name, resolved to an index when registering or browsing
+    Name(String),
+}
+
+impl Interface {
+    /// Resolves the interface index, None if there's no interface with the given name
+    pub(crate) fn index(&self) -> Option<u32> {
+        match self {
+            Interface::Index(index) => Some(*index),
+            Interface::Name(name) => {
+                let c_name = CString::new(name.as_str()).ok()?;
+                match unsafe { os::if_nametoindex(c_name.as_ptr()) } {
+                    0 => None,
+                    index => Some(index),
+                }
+            }
+        }
+    }
+}
+
+/// Resolves an optional interface to its index, 0 (all interfaces) if none
+///
+/// Fails with the interface name if there's no interface by that name.
+pub(crate) fn interface_index(interface: Option<&Interface>) -> Result<u32, String> {
+    match interface {
+        Some(Interface::Index(index)) => Ok(*index),
+        Some(interface @ Interface::Name(name)) => interface.index().ok_or_else(|| name.clone()),
+        None => Ok(0),
+    }
+}
+
+impl From<u32> for Interface {
+    fn from(index: u32) -> Self {
+        Interface::Index(index)
+    }
+}
+impl From<&str> for Interface {
+    fn from(name: &str) -> Self {
+        Interface::Name(name.to_string())
+    }
+}
+impl From<String> for Interface {
+    fn from(name: String) -> Self {
+        Interface::Name(name)
+    }
+}
+
+/// Looks up the OS name of an interface index, None for 0 (all interfaces) or unknown indexes
+pub(crate) fn interface_name(index: u32) -> Option<String> {
+    if index == 0 {
+        return None;
+    }
+    let mut buffer = [0 as c_char; NAME_BUFFER_LEN];
+    let name = unsafe { os::if_indextoname(index, buffer.as_mut_ptr()) };
+    if name.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(target_os = "windows"))]
+mod os {
+    pub use libc::{if_indextoname, if_nametoindex};
+}
+#[cfg(target_os = "windows")]
+mod os {
+    pub use winapi::shared::netioapi::{if_indextoname, if_nametoindex};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interfaces() {
+        assert_eq!(Interface::from(3).index(), Some(3));
+        assert_eq!(Interface::from("not-an-interface0").index(), None);
+        assert_eq!(interface_name(0), None);
+        assert_eq!(interface_index(None), Ok(0));
+        assert_eq!(
+            interface_index(Some(&Interface::from("not-an-interface0"))),
+            Err("not-an-interface0".to_string())
+        );
+    }
+}
@@ -2,18 +2,41 @@
 
 #![forbid(missing_docs)]
 
+#[cfg(all(feature = "tokio", feature = "win-bonjour", target_os = "windows"))]
+compile_error!("The tokio feature isn't supported with win-bonjour, as it needs AsyncFd");
+
 // pub mod browser;
 mod browse;
+mod error;
 mod ffi;
+mod full_name;
+mod interface;
 mod non_blocking;
 mod os;
+mod record;
 mod register;
+mod service_type;
+mod status;
+mod subtype;
+mod txt;
 
 pub use crate::browse::{
-    BrowseError, Service, ServiceBrowser, ServiceBrowserBuilder, ServiceEventType,
+    DiscoveredService, IntoIter, Iter, Service, ServiceBrowser, ServiceBrowserBuilder,
+    ServiceEventType,
 };
-pub use crate::os::{RegisteredDnsService, RegistrationError};
-pub use crate::register::DNSServiceBuilder;
+pub use crate::error::{BrowseError, Error, RegistrationError, ServiceErrorKind};
+pub use crate::full_name::{FullNameError, ServiceFullName};
+pub use crate::interface::Interface;
+#[cfg(feature = "tokio")]
+pub use crate::os::ServiceStream;
+pub use crate::os::{RecordHandle, RegisteredDnsService, Runtime};
+pub use crate::record::{RecordData, RecordError};
+pub use crate::register::{DNSServiceBuilder, RegistrationEvent};
+pub use crate::service_type::{RegType, ServiceProtocol, ServiceType, ServiceTypeError};
+pub use crate::status::ConnectionStatus;
+#[cfg(feature = "serde")]
+pub use crate::txt::{from_txt_record, to_txt_record, TXTSerdeError};
+pub use crate::txt::{TXTError, TXTRecord, TXTValue};
 
 #[macro_use]
 extern crate log;
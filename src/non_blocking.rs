@@ -1,3 +1,15 @@
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+#[cfg(feature = "async-io")]
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::mpsc::{RecvTimeoutError, TryRecvError},
+    task::Poll,
+    time::Duration,
+};
+
 #[cfg(all(target_os = "windows", feature = "win-bonjour"))]
 mod os {
     use super::*;
@@ -31,12 +43,37 @@ mod os {
             // TODO: figure out why no flags are set, or maybe switch to IOCP
             Ok(true)
         } else if r == SOCKET_ERROR {
-            Err(std::io::Error::from_raw_os_error(r))
+            Err(std::io::Error::last_os_error())
         } else {
             trace!("Nothing ready");
             Ok(false)
         }
     }
+
+    pub type Socket = SOCKET;
+
+    /// Waits until any of the sockets are readable, forever without a timeout
+    pub fn sockets_ready(
+        sockets: &[SOCKET],
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Vec<bool>, std::io::Error> {
+        let mut sockets: Vec<WSAPOLLFD> = sockets
+            .iter()
+            .map(|&fd| WSAPOLLFD {
+                fd,
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().min(i32::MAX as u128) as i32
+        });
+        let r = unsafe { WSAPoll(sockets.as_mut_ptr(), sockets.len() as u32, timeout) };
+        if r == SOCKET_ERROR {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(sockets.iter().map(|socket| socket.revents != 0).collect())
+    }
 }
 #[cfg(not(target_os = "windows"))]
 mod os {
@@ -46,23 +83,248 @@ mod os {
     ) -> Result<bool, std::io::Error> {
         unsafe {
             let fd = socket;
+            // microseconds past the seconds, as select rejects a whole timeout in tv_usec
             let mut timeout = libc::timeval {
                 tv_sec: timeout.as_secs() as _,
-                tv_usec: timeout.as_micros() as _,
+                tv_usec: timeout.subsec_micros() as _,
             };
             let mut read_set = std::mem::zeroed();
             libc::FD_ZERO(&mut read_set);
             libc::FD_SET(fd, &mut read_set);
-            libc::select(
+            let r = libc::select(
                 fd + 1,
                 &mut read_set,
                 std::ptr::null_mut(),
                 std::ptr::null_mut(),
                 &mut timeout,
             );
-            Ok(libc::FD_ISSET(fd, &read_set))
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                return Err(e);
+            }
+            Ok(r > 0 && libc::FD_ISSET(fd, &read_set))
+        }
+    }
+
+    pub type Socket = i32;
+
+    /// Waits until any of the sockets are readable, forever without a timeout
+    pub fn sockets_ready(
+        sockets: &[i32],
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Vec<bool>, std::io::Error> {
+        let mut sockets: Vec<libc::pollfd> = sockets
+            .iter()
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_millis().min(i32::MAX as u128) as i32
+        });
+        let r = unsafe { libc::poll(sockets.as_mut_ptr(), sockets.len() as _, timeout) };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(vec![false; sockets.len()]);
+            }
+            return Err(e);
+        }
+        Ok(sockets.iter().map(|socket| socket.revents != 0).collect())
+    }
+}
+#[cfg(any(not(target_os = "windows"), feature = "win-bonjour"))]
+pub use os::{socket_is_ready, sockets_ready, Socket};
+
+/// UDP socket connected to itself, readable once woken, to wake a thread polling sockets
+///
+/// Wakes as a `Waker` too, so a thread can wait on channels along with sockets.
+#[cfg(any(not(target_os = "windows"), feature = "win-bonjour"))]
+pub(crate) struct WakeSocket(std::net::UdpSocket);
+#[cfg(any(not(target_os = "windows"), feature = "win-bonjour"))]
+impl WakeSocket {
+    pub(crate) fn new() -> Result<Self, std::io::Error> {
+        let socket = std::net::UdpSocket::bind(("127.0.0.1", 0))?;
+        socket.connect(socket.local_addr()?)?;
+        socket.set_nonblocking(true)?;
+        Ok(WakeSocket(socket))
+    }
+    /// Makes the socket readable, waking a thread polling it
+    pub(crate) fn notify(&self) {
+        if let Err(e) = self.0.send(&[0]) {
+            error!("Error waking thread: {}", e);
         }
     }
+    /// Reads every wake sent so far, so it's only readable once woken again
+    pub(crate) fn drain(&self) {
+        let mut buffer = [0; 16];
+        while self.0.recv(&mut buffer).is_ok() {}
+    }
+    #[cfg(not(target_os = "windows"))]
+    pub(crate) fn socket(&self) -> Socket {
+        use std::os::unix::io::AsRawFd;
+        self.0.as_raw_fd()
+    }
+    #[cfg(target_os = "windows")]
+    pub(crate) fn socket(&self) -> Socket {
+        use std::os::windows::io::AsRawSocket;
+        self.0.as_raw_socket() as _
+    }
 }
 #[cfg(any(not(target_os = "windows"), feature = "win-bonjour"))]
-pub use os::socket_is_ready;
+impl std::task::Wake for WakeSocket {
+    fn wake(self: Arc<Self>) {
+        self.notify();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.notify();
+    }
+}
+
+/// Waker of a task waiting on a channel, woken as values are sent
+#[derive(Default)]
+pub(crate) struct ChannelWaker(Mutex<Option<Waker>>);
+impl ChannelWaker {
+    /// Wakes the given waker when the next value is sent, replacing any previous one
+    #[cfg_attr(not(any(feature = "tokio", feature = "async-io")), allow(dead_code))]
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut current = match self.0.lock() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if !current
+            .as_ref()
+            .is_some_and(|current| current.will_wake(waker))
+        {
+            *current = Some(waker.clone());
+        }
+    }
+    fn wake(&self) {
+        let waker = match self.0.lock() {
+            Ok(mut current) => current.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Sends on a channel, waking any task waiting on it
+pub(crate) struct WakingSender<T> {
+    tx: Sender<T>,
+    waker: Arc<ChannelWaker>,
+}
+impl<T> WakingSender<T> {
+    pub(crate) fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let result = self.tx.send(value);
+        self.waker.wake();
+        result
+    }
+}
+impl<T> Clone for WakingSender<T> {
+    fn clone(&self) -> Self {
+        WakingSender {
+            tx: self.tx.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+impl<T> Drop for WakingSender<T> {
+    /// Wakes the waiting task, so it notices the channel's disconnected
+    fn drop(&mut self) {
+        self.waker.wake();
+    }
+}
+
+/// Unbounded channel that wakes a waiting task as values are sent, so senders on the daemon's
+/// callbacks never block or drop values
+pub(crate) fn waking_channel<T>() -> (WakingSender<T>, Receiver<T>, Arc<ChannelWaker>) {
+    let (tx, rx) = channel();
+    let waker = Arc::new(ChannelWaker::default());
+    let sender = WakingSender {
+        tx,
+        waker: waker.clone(),
+    };
+    (sender, rx, waker)
+}
+
+/// Receives a value without blocking the executor, waiting up to the timeout
+///
+/// Takes the receiver, as a borrowed one would keep the future from being `Send`.
+#[cfg(feature = "async-io")]
+pub(crate) async fn recv_timeout_async<T>(
+    rx: Receiver<T>,
+    waker: &ChannelWaker,
+    timeout: Duration,
+) -> Result<T, RecvTimeoutError> {
+    let mut timer = async_io::Timer::after(timeout);
+    std::future::poll_fn(move |cx| {
+        // registered first, so a value sent after checking still wakes the task
+        waker.register(cx.waker());
+        match rx.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Disconnected) => {
+                return Poll::Ready(Err(RecvTimeoutError::Disconnected))
+            }
+            Err(TryRecvError::Empty) => {}
+        }
+        match Pin::new(&mut timer).poll(cx) {
+            Poll::Ready(_) => Poll::Ready(Err(RecvTimeoutError::Timeout)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Socket owned by a DNSServiceRef, borrowed to wait on it with async-io
+#[cfg(all(
+    feature = "async-io",
+    any(not(target_os = "windows"), feature = "win-bonjour")
+))]
+struct BorrowedSocket(Socket);
+#[cfg(all(feature = "async-io", not(target_os = "windows")))]
+impl std::os::unix::io::AsFd for BorrowedSocket {
+    fn as_fd(&self) -> std::os::unix::io::BorrowedFd<'_> {
+        // SAFETY: the DNSServiceRef owning the socket outlives waiting on it
+        unsafe { std::os::unix::io::BorrowedFd::borrow_raw(self.0) }
+    }
+}
+#[cfg(all(feature = "async-io", target_os = "windows", feature = "win-bonjour"))]
+impl std::os::windows::io::AsSocket for BorrowedSocket {
+    fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+        // SAFETY: the DNSServiceRef owning the socket outlives waiting on it
+        unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(self.0 as _) }
+    }
+}
+
+/// Waits without blocking the executor until a socket owned by a DNSServiceRef is readable,
+/// returning false if it isn't by the deadline
+///
+/// The socket's left in blocking mode, as dns_sd expects.
+#[cfg(all(
+    feature = "async-io",
+    any(not(target_os = "windows"), feature = "win-bonjour")
+))]
+pub(crate) async fn socket_readable_async(
+    socket: Socket,
+    deadline: std::time::Instant,
+) -> Result<bool, std::io::Error> {
+    let socket = async_io::Async::new_nonblocking(BorrowedSocket(socket))?;
+    let mut timer = async_io::Timer::at(deadline);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(result) = socket.poll_readable(cx) {
+            return Poll::Ready(result.map(|()| true));
+        }
+        match Pin::new(&mut timer).poll(cx) {
+            Poll::Ready(_) => Poll::Ready(Ok(false)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
@@ -1,3 +1,5 @@
 pub mod browse;
 pub mod register;
-mod txt;
+pub mod runtime;
+#[cfg(feature = "tokio")]
+pub mod stream;
@@ -1,21 +1,40 @@
+use crate::browse::{DiscoveredService, Service, ServiceEventType};
+use crate::error::BrowseError;
 use crate::ffi::apple as ffi;
-// use std::collections::HashMap;
-use crate::browse::{Service, ServiceEventType};
 use crate::ffi::apple::kDNSServiceErr_NoError;
+use crate::interface::{interface_index, interface_name};
+use crate::non_blocking::{
+    sockets_ready, waking_channel, ChannelWaker, Socket, WakeSocket, WakingSender,
+};
+use crate::os::apple::runtime::{initial_ref, lock, Connection, Operation, Runtime};
+use crate::status::{Backoff, ConnectionStatus};
+use crate::subtype::regtype_with_subtypes;
+use crate::txt::TXTRecord;
 use crate::ServiceBrowserBuilder;
+use std::cell::{Cell, OnceCell, RefCell};
 use std::collections::HashMap;
 use std::ffi::{c_void, CStr, CString};
-use std::io::{Error as IoError, ErrorKind};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::os::raw::c_char;
 use std::ptr;
-use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
-use std::time::Duration;
-use thiserror::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+macro_rules! mut_void_ptr {
+    ($var:expr) => {
+        $var as *mut _ as *mut c_void
+    };
+}
+
+/// How long the resolver thread waits for a discovered service to resolve before giving up on it
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
 
 impl From<ffi::DNSServiceFlags> for ServiceEventType {
     fn from(flags: ffi::DNSServiceFlags) -> Self {
-        if flags & ffi::kDNSServiceFlagsAdd as u32 != 0 {
+        if flags & ffi::kDNSServiceFlagsAdd != 0 {
             ServiceEventType::Added
         } else {
             ServiceEventType::Removed
@@ -23,25 +42,6 @@ impl From<ffi::DNSServiceFlags> for ServiceEventType {
     }
 }
 
-/// Common error for DNS-SD service
-#[derive(Debug, Error)]
-pub enum BrowseError {
-    /// Invalid input string
-    #[error("Invalid string argument, must be C string compatible")]
-    InvalidString,
-    /// Unexpected invalid strings from C API
-    #[error("DNSSD API returned invalid UTF-8 string")]
-    InternalInvalidString,
-    /// Error from DNSSD service
-    #[error("DNSSD Error: {0}")]
-    ServiceError(i32),
-    /// IO error
-    #[error("IO Error: {0}")]
-    IoError(#[from] IoError),
-    /// Timeout error when waiting for more data from browser
-    #[error("Timeout waiting for more data")]
-    Timeout,
-}
 /// Apple based DNS-SD result type
 pub type Result<T, E = BrowseError> = std::result::Result<T, E>;
 
@@ -56,12 +56,12 @@ unsafe extern "C" fn browse_callback(
     context: *mut c_void,
 ) {
     if !context.is_null() {
-        let tx_ptr: *mut SyncSender<Result<DiscoveredService>> = context as _;
+        let tx_ptr: *mut WakingSender<Result<DiscoveredService>> = context as _;
         let tx = &*tx_ptr;
 
         // shouldn't need any other args if there's an error
         if error_code != 0 {
-            match tx.try_send(Err(BrowseError::ServiceError(error_code))) {
+            match tx.send(Err(BrowseError::service(error_code))) {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Error sending service notification on channel: {:?}", e);
@@ -75,15 +75,15 @@ unsafe extern "C" fn browse_callback(
             let c_str: &CStr = CStr::from_ptr(service_name);
             let service_name: &str = c_str
                 .to_str()
-                .map_err(|_| BrowseError::InternalInvalidString)?;
+                .map_err(|_| BrowseError::InternalInvalidString { field: "name" })?;
             let c_str: &CStr = CStr::from_ptr(regtype);
             let regtype: &str = c_str
                 .to_str()
-                .map_err(|_| BrowseError::InternalInvalidString)?;
+                .map_err(|_| BrowseError::InternalInvalidString { field: "regtype" })?;
             let c_str: &CStr = CStr::from_ptr(reply_domain);
             let reply_domain: &str = c_str
                 .to_str()
-                .map_err(|_| BrowseError::InternalInvalidString)?;
+                .map_err(|_| BrowseError::InternalInvalidString { field: "domain" })?;
             Ok((
                 service_name.to_owned(),
                 regtype.to_owned(),
@@ -100,14 +100,14 @@ unsafe extern "C" fn browse_callback(
                     event_type: flags.into(),
                 };
                 trace!("Informing of discovered service: {:?}", service);
-                match tx.try_send(Ok(service)) {
+                match tx.send(Ok(service)) {
                     Ok(_) => {}
                     Err(e) => {
                         error!("Error sending service notification on channel: {:?}", e);
                     }
                 }
             }
-            Err(e) => match tx.try_send(Err(e)) {
+            Err(e) => match tx.send(Err(e)) {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Error sending service notification on channel: {:?}", e);
@@ -117,27 +117,15 @@ unsafe extern "C" fn browse_callback(
     }
 }
 
-/// Encapsulates information about a service
-#[derive(Debug)]
-pub struct DiscoveredService {
-    /// Name of service, usually a user friendly name
-    pub name: String,
-    /// Registration type, i.e. _http._tcp.
-    pub regtype: String,
-    /// Interface index (unsure what this is for)
-    pub interface_index: u32,
-    /// Domain service is on, typically local.
-    pub domain: String,
-    /// Whether this service is being added or not
-    pub event_type: ServiceEventType,
-}
-
 fn service_from_resolved(discovered: DiscoveredService, resolved: Vec<ResolvedService>) -> Service {
     if resolved.len() > 1 {
         warn!("We resolved > 1 services, unsupported. using first");
     }
     let (port, hostname, txt_record) = match resolved.into_iter().next() {
-        Some(resolved) => (resolved.port, resolved.hostname, resolved.txt_record),
+        Some(resolved) => {
+            trace!("Using resolved service: {}", resolved.full_name);
+            (resolved.port, resolved.hostname, resolved.txt_record)
+        }
         None => (0, "".to_string(), None),
     };
     Service {
@@ -145,6 +133,7 @@ fn service_from_resolved(discovered: DiscoveredService, resolved: Vec<ResolvedSe
         domain: discovered.domain,
         regtype: discovered.regtype,
         interface_index: Some(discovered.interface_index),
+        interface_name: interface_name(discovered.interface_index),
         event_type: discovered.event_type,
         hostname,
         port,
@@ -152,131 +141,1188 @@ fn service_from_resolved(discovered: DiscoveredService, resolved: Vec<ResolvedSe
     }
 }
 
-fn resolver_thread(rx: Receiver<Result<DiscoveredService>>, tx: SyncSender<Result<Service>>) {
-    std::thread::Builder::new()
-        .name("astro-dnssd: resolver".into())
-        .spawn(move || loop {
-            match rx.recv_timeout(Duration::from_millis(250)) {
+/// Identifies a discovered service across browse events
+type ServiceKey = (String, String, String, u32);
+
+impl DiscoveredService {
+    fn key(&self) -> ServiceKey {
+        (
+            self.name.clone(),
+            self.regtype.clone(),
+            self.domain.clone(),
+            self.interface_index,
+        )
+    }
+}
+
+/// TXT record state shared with the query callback
+#[derive(Default)]
+struct TxtQueryContext {
+    /// Last TXT record known for the service
+    current: TXTRecord,
+    /// Changed records not yet sent on
+    updates: Vec<TXTRecord>,
+}
+impl TxtQueryContext {
+    /// Queues the TXT record data replied with if it changed
+    fn update(&mut self, data: &[u8]) {
+        match TXTRecord::from_bytes(data) {
+            Ok(txt) if txt != self.current => {
+                self.current = txt.clone();
+                self.updates.push(txt);
+            }
+            Ok(_txt) => {}
+            Err(e) => {
+                error!("Failed to get updated TXT record: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Long-lived TXT record query for a resolved service, to notice when its TXT record changes
+struct TxtQuery {
+    /// Null once lost with the runtime's connection
+    raw: ffi::DNSServiceRef,
+    /// Processed by a runtime on its connection, rather than polled
+    shared: bool,
+    context: Box<TxtQueryContext>,
+    name: String,
+    regtype: String,
+    domain: String,
+    interface_index: u32,
+    hostname: String,
+    port: u16,
+}
+
+impl TxtQuery {
+    /// Starts querying the TXT record of the resolved service, on the runtime's connection if given
+    fn start(
+        service: &Service,
+        full_name: &str,
+        interface_index: u32,
+        connection: Option<&Connection>,
+    ) -> Result<Self> {
+        let c_full_name = CString::new(full_name)
+            .map_err(|_| BrowseError::InvalidString { field: "full name" })?;
+        let mut context = Box::new(TxtQueryContext {
+            current: service.txt_record.clone().unwrap_or_default(),
+            updates: Vec::new(),
+        });
+        let (mut raw, flags) = initial_ref(connection).map_err(BrowseError::service)?;
+        let r = unsafe {
+            ffi::DNSServiceQueryRecord(
+                &mut raw,
+                flags,
+                interface_index,
+                c_full_name.as_ptr(),
+                ffi::kDNSServiceType_TXT as u16,
+                ffi::kDNSServiceClass_IN as u16,
+                Some(txt_query_callback),
+                mut_void_ptr!(context.as_mut()),
+            )
+        };
+        if r == ffi::kDNSServiceErr_Unsupported {
+            // Avahi's compat layer can't query records, but calls back a resolve again whenever
+            // the service changes, so keep resolving it instead
+            trace!(
+                "Querying records unsupported, resolving {} instead",
+                service.name
+            );
+            raw = unsafe { TxtQuery::resolve(service, interface_index, connection, &mut context)? };
+        } else if r != kDNSServiceErr_NoError {
+            return Err(BrowseError::service(r));
+        }
+        Ok(TxtQuery {
+            raw,
+            shared: connection.is_some(),
+            context,
+            name: service.name.clone(),
+            regtype: service.regtype.clone(),
+            domain: service.domain.clone(),
+            interface_index,
+            hostname: service.hostname.clone(),
+            port: service.port,
+        })
+    }
+    /// Starts a long-lived resolve of the service, noticing TXT record changes as it's called back
+    ///
+    /// # Safety
+    /// `context` must outlive the returned ref
+    unsafe fn resolve(
+        service: &Service,
+        interface_index: u32,
+        connection: Option<&Connection>,
+        context: &mut TxtQueryContext,
+    ) -> Result<ffi::DNSServiceRef> {
+        let name = CString::new(service.name.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "name" })?;
+        let regtype = CString::new(service.regtype.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "regtype" })?;
+        let domain = CString::new(service.domain.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "domain" })?;
+        let (mut raw, flags) = initial_ref(connection).map_err(BrowseError::service)?;
+        let r = ffi::DNSServiceResolve(
+            &mut raw,
+            flags,
+            interface_index,
+            name.as_ptr(),
+            regtype.as_ptr(),
+            domain.as_ptr(),
+            Some(txt_resolve_callback),
+            mut_void_ptr!(context),
+        );
+        if r != kDNSServiceErr_NoError {
+            return Err(BrowseError::service(r));
+        }
+        Ok(raw)
+    }
+    /// Socket of the query's own ref, to wait on replies when it isn't on a runtime
+    fn socket(&self) -> Socket {
+        unsafe { ffi::DNSServiceRefSockFD(self.raw) as _ }
+    }
+    /// Processes any pending replies without blocking, returning services with changed TXT records
+    fn poll(&mut self) -> Result<Vec<Service>> {
+        let socket = self.socket();
+        while !self.shared
+            && crate::non_blocking::socket_is_ready(socket, Duration::from_millis(0))?
+        {
+            let r = unsafe { ffi::DNSServiceProcessResult(self.raw) };
+            if r != kDNSServiceErr_NoError {
+                return Err(BrowseError::service(r));
+            }
+        }
+        let updates = std::mem::take(&mut self.context.updates);
+        let updates = updates
+            .into_iter()
+            .map(|txt| Service {
+                name: self.name.clone(),
+                regtype: self.regtype.clone(),
+                interface_index: Some(self.interface_index),
+                interface_name: interface_name(self.interface_index),
+                domain: self.domain.clone(),
+                hostname: self.hostname.clone(),
+                port: self.port,
+                txt_record: if txt.is_empty() { None } else { Some(txt) },
+                event_type: ServiceEventType::TxtUpdated,
+            })
+            .collect();
+        Ok(updates)
+    }
+}
+
+impl Drop for TxtQuery {
+    fn drop(&mut self) {
+        trace!("Stopping TXT query for {}", self.name);
+        if !self.raw.is_null() {
+            unsafe { ffi::DNSServiceRefDeallocate(self.raw) };
+        }
+    }
+}
+
+unsafe extern "C" fn txt_query_callback(
+    _sd_ref: ffi::DNSServiceRef,
+    flags: ffi::DNSServiceFlags,
+    _interface_index: u32,
+    error_code: ffi::DNSServiceErrorType,
+    _full_name: *const c_char,
+    _rrtype: u16,
+    _rrclass: u16,
+    rdlen: u16,
+    rdata: *const c_void,
+    _ttl: u32,
+    context: *mut c_void,
+) {
+    let context: &mut TxtQueryContext = &mut *(context as *mut TxtQueryContext);
+    if error_code != kDNSServiceErr_NoError {
+        error!("Error querying TXT record: {}", error_code);
+        return;
+    }
+    // removal of the old record comes alongside the new one, only additions matter
+    if flags & ffi::kDNSServiceFlagsAdd == 0 {
+        return;
+    }
+    let data: &[u8] = if rdlen > 0 && !rdata.is_null() {
+        std::slice::from_raw_parts(rdata as *const u8, rdlen as usize)
+    } else {
+        &[]
+    };
+    context.update(data);
+}
+
+/// Resolve callback for TXT queries falling back to resolving, called again as the service changes
+unsafe extern "C" fn txt_resolve_callback(
+    _sd_ref: ffi::DNSServiceRef,
+    _flags: ffi::DNSServiceFlags,
+    _interface_index: u32,
+    error_code: ffi::DNSServiceErrorType,
+    _full_name: *const c_char,
+    _host_target: *const c_char,
+    _port: u16,
+    txt_len: u16,
+    txt_record: *const u8,
+    context: *mut c_void,
+) {
+    let context: &mut TxtQueryContext = &mut *(context as *mut TxtQueryContext);
+    if error_code != kDNSServiceErr_NoError {
+        error!("Error resolving TXT record: {}", error_code);
+        return;
+    }
+    let data: &[u8] = if txt_len > 0 && !txt_record.is_null() {
+        std::slice::from_raw_parts(txt_record, txt_len as usize)
+    } else {
+        &[]
+    };
+    context.update(data);
+}
+
+/// Resolves discovered services on their own refs, querying their TXT records for changes once
+/// resolved, on the resolver thread or the caller's with manual processing
+struct Resolver {
+    rx: Receiver<Result<DiscoveredService>>,
+    /// Woken as services are discovered
+    rx_waker: Arc<ChannelWaker>,
+    resolves: Vec<(PendingResolve, Instant)>,
+    txt_queries: HashMap<ServiceKey, TxtQuery>,
+}
+impl Resolver {
+    fn new(rx: Receiver<Result<DiscoveredService>>, rx_waker: Arc<ChannelWaker>) -> Self {
+        Resolver {
+            rx,
+            rx_waker,
+            resolves: Vec::new(),
+            txt_queries: HashMap::new(),
+        }
+    }
+
+    /// Starts resolving newly discovered services, returning the services to send on as
+    /// resolves finish or time out, none once the browser's dropped
+    fn resolve(&mut self) -> Option<Vec<Result<Service>>> {
+        let mut services = Vec::new();
+        loop {
+            match self.rx.try_recv() {
+                Ok(Ok(service)) if service.event_type == ServiceEventType::Removed => {
+                    // the service is gone, so there's nothing left to resolve
+                    let key = service.key();
+                    let (removed, pending): (Vec<_>, _) = std::mem::take(&mut self.resolves)
+                        .into_iter()
+                        .partition(|(resolve, _deadline)| resolve.discovered.key() == key);
+                    self.resolves = pending;
+                    removed.into_iter().for_each(|(resolve, _deadline)| {
+                        resolve.finish();
+                    });
+                    self.txt_queries.remove(&key);
+                    services.push(Ok(service_from_resolved(service, Vec::new())));
+                }
                 Ok(Ok(service)) => {
                     trace!("Got new service: {:?}, resolving...", service);
-                    match service.resolve() {
-                        Ok(resolved) => {
-                            trace!("Resolved: {:?}", resolved);
-                            let service = service_from_resolved(service, resolved);
-                            if let Err(_e) = tx.send(Ok(service)) {
-                                error!("Error sending resolved service, disconnected channel, exiting thread");
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error resolving: {:?}", e);
-                            if let Err(_e) = tx.send(Err(e)) {
-                                error!("Error sending resolved service, disconnected channel, exiting thread");
-                                break;
-                            }
-                        }
+                    match PendingResolve::start(service, None) {
+                        Ok(resolve) => self
+                            .resolves
+                            .push((resolve, Instant::now() + RESOLVE_TIMEOUT)),
+                        Err(e) => services.push(Err(e)),
                     }
                 }
-                Ok(Err(e)) => {
-                    if let Err(_e) = tx.send(Err(e)) {
-                        error!("Error sending resolved service, disconnected channel, exiting thread");
-                        break;
+                Ok(Err(e)) => services.push(Err(e)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+
+        let now = Instant::now();
+        let (finished, pending): (Vec<_>, _) = std::mem::take(&mut self.resolves)
+            .into_iter()
+            .partition(|(resolve, deadline)| !resolve.context.more_coming || *deadline <= now);
+        self.resolves = pending;
+        for (resolve, _deadline) in finished {
+            if resolve.context.more_coming {
+                warn!("Timed out resolving {}", resolve.discovered.name);
+                resolve.finish();
+                services.push(Err(BrowseError::Timeout));
+                continue;
+            }
+            let (discovered, resolved) = resolve.finish();
+            trace!("Resolved: {:?}", resolved);
+            let key = discovered.key();
+            let interface_index = discovered.interface_index;
+            let full_name = resolved.first().map(|r| r.full_name.clone());
+            let service = service_from_resolved(discovered, resolved);
+            if let (ServiceEventType::Added, Some(full_name)) = (service.event_type, full_name) {
+                match TxtQuery::start(&service, &full_name, interface_index, None) {
+                    Ok(query) => {
+                        self.txt_queries.insert(key, query);
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error starting TXT query, changes won't be noticed: {:?}",
+                            e
+                        );
                     }
                 }
-                Err(RecvTimeoutError::Timeout) => {}
-                Err(RecvTimeoutError::Disconnected) => {
-                    warn!("Resolver channel disconnected, exiting thread as we're likely stopped/dropped");
-                    break;
+            }
+            services.push(Ok(service));
+        }
+        Some(services)
+    }
+
+    /// Sockets of the resolves & TXT queries in progress, in the order `process` takes them
+    fn sockets(&self) -> Vec<Socket> {
+        self.resolves
+            .iter()
+            .map(|(resolve, _deadline)| resolve.socket())
+            .chain(self.txt_queries.values().map(TxtQuery::socket))
+            .collect()
+    }
+
+    /// Time until the next resolve times out, if resolving
+    fn timeout(&self) -> Option<Duration> {
+        self.resolves
+            .iter()
+            .map(|(_resolve, deadline)| deadline.saturating_duration_since(Instant::now()))
+            .min()
+    }
+
+    /// Processes replies to the sockets that are ready, as returned by `sockets`, returning
+    /// changes to TXT records
+    fn process(&mut self, ready: &[bool]) -> Vec<Service> {
+        let (resolves_ready, queries_ready) = ready.split_at(self.resolves.len().min(ready.len()));
+        for ((resolve, _deadline), _ready) in self
+            .resolves
+            .iter_mut()
+            .zip(resolves_ready)
+            .filter(|(_resolve, ready)| **ready)
+        {
+            resolve.process();
+        }
+        let mut updates = Vec::new();
+        let mut failed = Vec::new();
+        for ((key, query), _ready) in self
+            .txt_queries
+            .iter_mut()
+            .zip(queries_ready)
+            .filter(|(_query, ready)| **ready)
+        {
+            match query.poll() {
+                Ok(services) => updates.extend(services),
+                Err(e) => {
+                    error!(
+                        "Error processing TXT query for {}, stopping it: {:?}",
+                        query.name, e
+                    );
+                    failed.push(key.clone());
+                }
+            }
+        }
+        for key in failed {
+            self.txt_queries.remove(&key);
+        }
+        updates
+    }
+}
+// refs are only used by whichever thread holds the resolver
+unsafe impl Send for Resolver {}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        for (resolve, _deadline) in std::mem::take(&mut self.resolves) {
+            resolve.finish();
+        }
+    }
+}
+
+/// Resolves discovered services on a thread, sending them on once resolved along with changes
+/// to their TXT records, until the browser's dropped
+fn resolver_thread(mut resolver: Resolver, tx: WakingSender<Result<Service>>) -> Result<()> {
+    let wake = Arc::new(WakeSocket::new()?);
+    std::thread::Builder::new()
+        .name("astro-dnssd: resolver".into())
+        .spawn(move || {
+            let waker = Waker::from(wake.clone());
+            let result = loop {
+                // registered first, so a service sent after checking still wakes the thread
+                resolver.rx_waker.register(&waker);
+                let services = match resolver.resolve() {
+                    Some(services) => services,
+                    None => {
+                        warn!("Resolver channel disconnected, exiting thread as we're likely stopped/dropped");
+                        break Ok(());
+                    }
+                };
+                if services.into_iter().any(|service| tx.send(service).is_err()) {
+                    break Ok(());
+                }
+
+                // waits on new services, resolves & TXT queries, until the next resolve times out
+                let sockets: Vec<Socket> = std::iter::once(wake.socket())
+                    .chain(resolver.sockets())
+                    .collect();
+                let ready = match sockets_ready(&sockets, resolver.timeout()) {
+                    Ok(ready) => ready,
+                    Err(e) => break Err(e),
+                };
+                wake.drain();
+                let updates = resolver.process(&ready[1..]);
+                if updates.into_iter().any(|service| tx.send(Ok(service)).is_err()) {
+                    break Ok(());
                 }
+            };
+            if let Err(e) = result {
+                error!("Error waiting on replies, exiting resolver thread: {}", e);
+                let _ = tx.send(Err(e.into()));
             }
-        }).expect("Failed to start resolver thread");
+        })?;
+    Ok(())
+}
+
+/// What to browse for, kept to browse again after losing the daemon
+#[derive(Clone)]
+struct BrowseParams {
+    /// Type, including any subtype
+    regtype: CString,
+    domain: Option<CString>,
+    interface_index: u32,
+}
+impl BrowseParams {
+    /// Starts browsing, on the runtime's connection if given, returning the new DNSServiceRef
+    fn browse(
+        &self,
+        connection: Option<&Connection>,
+        tx: *mut WakingSender<Result<DiscoveredService>>,
+    ) -> Result<ffi::DNSServiceRef> {
+        let (mut raw, flags) = initial_ref(connection).map_err(BrowseError::service)?;
+        let r = unsafe {
+            ffi::DNSServiceBrowse(
+                &mut raw as _,
+                flags,
+                self.interface_index,
+                self.regtype.as_ptr(),
+                self.domain.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
+                Some(browse_callback),
+                tx as _,
+            )
+        };
+        if r != kDNSServiceErr_NoError {
+            error!("DNSServiceBrowser error: {}", r);
+            return Err(BrowseError::service(r));
+        }
+        Ok(raw)
+    }
+}
+
+/// Resolve of a discovered service, on a runtime's connection if started on one
+///
+/// Not deallocated on drop, as it's dropped without it when the connection is lost.
+struct PendingResolve {
+    raw: ffi::DNSServiceRef,
+    context: Box<PendingResolution>,
+    discovered: DiscoveredService,
+}
+impl PendingResolve {
+    fn start(discovered: DiscoveredService, connection: Option<&Connection>) -> Result<Self> {
+        let mut context = Box::<PendingResolution>::default();
+        let raw = unsafe { discovered.start_resolve(connection, context.as_mut())? };
+        Ok(PendingResolve {
+            raw,
+            context,
+            discovered,
+        })
+    }
+    /// Socket of the resolve's own ref, when it isn't on a runtime
+    fn socket(&self) -> Socket {
+        unsafe { ffi::DNSServiceRefSockFD(self.raw) as _ }
+    }
+    /// Processes a reply to the resolve's own ref, finishing it if the daemon failed
+    fn process(&mut self) {
+        let r = unsafe { ffi::DNSServiceProcessResult(self.raw) };
+        if r != kDNSServiceErr_NoError {
+            error!(
+                "Error processing resolve of {}: {}",
+                self.discovered.name, r
+            );
+            self.context.more_coming = false;
+            self.context.error = Some(r);
+        }
+    }
+    /// Stops resolving, returning the discovered service with what it resolved to
+    fn finish(self) -> (DiscoveredService, Vec<ResolvedService>) {
+        unsafe { ffi::DNSServiceRefDeallocate(self.raw) };
+        (self.discovered, self.context.results)
+    }
+}
+
+/// Browse state on a runtime, only touched with its connection locked
+struct SharedBrowseState {
+    /// Null once lost with the connection
+    raw: ffi::DNSServiceRef,
+    /// Services from the browse callback, resolved before being sent on, none when browse-only
+    /// as the browser takes them unresolved
+    discovered: Option<Receiver<Result<DiscoveredService>>>,
+    resolves: Vec<PendingResolve>,
+    txt_queries: HashMap<ServiceKey, TxtQuery>,
+}
+
+/// Browse on a runtime, resolving & querying TXT records on its connection instead of a thread
+pub(super) struct SharedBrowse {
+    runtime: Runtime,
+    params: BrowseParams,
+    /// Browse callback context, owned by the `ServiceBrowser`
+    raw_tx: *mut WakingSender<Result<DiscoveredService>>,
+    tx: WakingSender<Result<Service>>,
+    state: Mutex<SharedBrowseState>,
+    stopped: AtomicBool,
+}
+// state is only used with the runtime's connection locked, the sender outlives the browse
+unsafe impl Send for SharedBrowse {}
+unsafe impl Sync for SharedBrowse {}
+
+impl SharedBrowse {
+    /// Starts browsing on the runtime
+    fn start(
+        runtime: Runtime,
+        params: BrowseParams,
+        raw_tx: *mut WakingSender<Result<DiscoveredService>>,
+        discovered: Option<Receiver<Result<DiscoveredService>>>,
+        tx: WakingSender<Result<Service>>,
+    ) -> Result<Arc<Self>> {
+        let connection = runtime.connection();
+        let raw = params.browse(Some(&connection), raw_tx)?;
+        let browse = Arc::new(SharedBrowse {
+            runtime: runtime.clone(),
+            params,
+            raw_tx,
+            tx,
+            state: Mutex::new(SharedBrowseState {
+                raw,
+                discovered,
+                resolves: Vec::new(),
+                txt_queries: HashMap::new(),
+            }),
+            stopped: AtomicBool::new(false),
+        });
+        let operation: std::sync::Weak<SharedBrowse> = Arc::downgrade(&browse);
+        runtime.add(operation);
+        drop(connection);
+        Ok(browse)
+    }
+    /// Sends a service on, queueing it until the browser receives it
+    fn send(&self, service: Result<Service>) {
+        if let Err(e) = self.tx.send(service) {
+            trace!("Browser is gone, not sending {:?}", e.0);
+        }
+    }
+    /// Resolves newly discovered services, sending on finished resolves & TXT record changes
+    fn process(&self, state: &mut SharedBrowseState, connection: &Connection) {
+        let discovered: Vec<_> = state
+            .discovered
+            .iter()
+            .flat_map(Receiver::try_iter)
+            .collect();
+        for discovered in discovered {
+            let service = match discovered {
+                Ok(service) => service,
+                Err(e) => {
+                    self.send(Err(e));
+                    continue;
+                }
+            };
+            if service.event_type == ServiceEventType::Removed {
+                // the service is gone, so there's nothing left to resolve
+                let key = service.key();
+                let (removed, resolves): (Vec<PendingResolve>, _) =
+                    std::mem::take(&mut state.resolves)
+                        .into_iter()
+                        .partition(|resolve| resolve.discovered.key() == key);
+                state.resolves = resolves;
+                for resolve in removed {
+                    resolve.finish();
+                }
+                state.txt_queries.remove(&key);
+                self.send(Ok(service_from_resolved(service, Vec::new())));
+                continue;
+            }
+            trace!("Got new service: {:?}, resolving...", service);
+            match PendingResolve::start(service, Some(connection)) {
+                Ok(resolve) => state.resolves.push(resolve),
+                Err(e) => self.send(Err(e)),
+            }
+        }
+        let (resolved, resolves): (Vec<PendingResolve>, _) = std::mem::take(&mut state.resolves)
+            .into_iter()
+            .partition(|resolve| !resolve.context.more_coming);
+        state.resolves = resolves;
+        for resolve in resolved {
+            let (discovered, resolved) = resolve.finish();
+            trace!("Resolved: {:?}", resolved);
+            let key = discovered.key();
+            let interface_index = discovered.interface_index;
+            let full_name = resolved.first().map(|r| r.full_name.clone());
+            let service = service_from_resolved(discovered, resolved);
+            if let Some(full_name) = full_name {
+                match TxtQuery::start(&service, &full_name, interface_index, Some(connection)) {
+                    Ok(query) => {
+                        state.txt_queries.insert(key, query);
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error starting TXT query, changes won't be noticed: {:?}",
+                            e
+                        );
+                    }
+                }
+            }
+            self.send(Ok(service));
+        }
+        let mut updates = Vec::new();
+        for query in state.txt_queries.values_mut() {
+            match query.poll() {
+                Ok(services) => updates.extend(services),
+                Err(e) => error!("Error getting TXT updates for {}: {:?}", query.name, e),
+            }
+        }
+        updates
+            .into_iter()
+            .for_each(|service| self.send(Ok(service)));
+    }
+    /// Stops browsing, along with any resolves & TXT queries
+    fn stop(&self) {
+        let _connection = self.runtime.connection();
+        self.stopped.store(true, Ordering::SeqCst);
+        let mut state = lock(&self.state);
+        if !state.raw.is_null() {
+            unsafe { ffi::DNSServiceRefDeallocate(state.raw) };
+            state.raw = ptr::null_mut();
+        }
+        for resolve in std::mem::take(&mut state.resolves) {
+            resolve.finish();
+        }
+        state.txt_queries.clear();
+    }
+}
+impl Operation for SharedBrowse {
+    fn after_process(&self, connection: &Connection) {
+        if self.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut state = lock(&self.state);
+        self.process(&mut state, connection);
+    }
+    fn lost(&self) {
+        // the refs went with the connection, so they're dropped without deallocating
+        let mut state = lock(&self.state);
+        state.raw = ptr::null_mut();
+        state.resolves.clear();
+        for query in state.txt_queries.values_mut() {
+            query.raw = ptr::null_mut();
+        }
+        state.txt_queries.clear();
+    }
+    fn restart(&self, connection: &Connection) {
+        if self.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        match self.params.browse(Some(connection), self.raw_tx) {
+            Ok(raw) => lock(&self.state).raw = raw,
+            Err(e) => {
+                error!("Error browsing again: {:?}", e);
+                self.send(Err(e));
+            }
+        }
+    }
 }
 
 /// Main service browser, calls callback upon discovery of service
 pub struct ServiceBrowser {
-    /// Raw DNS-SD service reference
-    raw: ffi::DNSServiceRef,
+    /// Raw DNS-SD service reference, null while reconnecting or browsing on a runtime
+    pub(super) raw: Cell<ffi::DNSServiceRef>,
     /// Receiver to receive successfully discovered & resolved services from
-    rx: Receiver<Result<Service>>,
-    /// Raw pointer the browse callback uses for the SyncSender, to use with Box::from_raw() during Drop
-    raw_tx: *mut SyncSender<Result<DiscoveredService>>,
+    pub(super) rx: Receiver<Result<Service>>,
+    /// Woken as services are sent on to `rx`
+    pub(super) waker: Arc<ChannelWaker>,
+    /// Services straight from the browse callback, unresolved, when browse-only
+    discovered: Option<Receiver<Result<DiscoveredService>>>,
+    /// Set once `next_service` has failed with `Error::BrowseOnly`, so iterators end after it
+    browse_only_reported: Cell<bool>,
+    /// Wakes `next_service` as services are sent on, made the first time it's needed
+    wake: OnceCell<Arc<WakeSocket>>,
+    /// Raw pointer the browse callback uses for the sender, to use with Box::from_raw() during Drop
+    raw_tx: *mut WakingSender<Result<DiscoveredService>>,
+    /// Browsed for again after losing the daemon
+    params: BrowseParams,
+    /// Backoff & time of the next attempt while reconnecting
+    reconnect: RefCell<Option<(Backoff, Instant)>>,
+    /// Browse on a runtime, which processes replies & reconnects instead
+    pub(super) shared: Option<Arc<SharedBrowse>>,
+    /// Resolver & the sender for its services with manual processing, run by the caller instead
+    /// of a thread, none if browse-only
+    manual: RefCell<Option<(Resolver, WakingSender<Result<Service>>)>>,
 }
 
 impl ServiceBrowser {
     /// Returns socket to mDNS service, use with select()
+    ///
+    /// It's the socket of the browser's own connection, or the runtime's if browsing on one,
+    /// which changes after connecting again once the daemon connection's lost.
     pub fn socket(&self) -> i32 {
-        unsafe { ffi::DNSServiceRefSockFD(self.raw) }
+        match &self.shared {
+            Some(shared) => shared.runtime.socket() as _,
+            None => unsafe { ffi::DNSServiceRefSockFD(self.raw.get()) },
+        }
+    }
+
+    /// Sockets to watch for replies to process with `process_ready`
+    ///
+    /// With `with_manual_processing` resolves & TXT queries each have a socket of their own, so
+    /// these change as services are found & should be fetched again after processing. Otherwise
+    /// it's just `socket`.
+    pub fn sockets(&self) -> Vec<i32> {
+        let mut sockets = vec![self.socket()];
+        if let Some((resolver, _tx)) = self.manual.borrow().as_ref() {
+            for socket in resolver.sockets() {
+                sockets.push(socket as _);
+            }
+        }
+        sockets
+    }
+
+    /// True if resolving on the caller's thread, with manual processing
+    pub(super) fn is_manual(&self) -> bool {
+        self.manual.borrow().is_some()
+    }
+
+    /// Moves resolving with manual processing onto a thread, for a stream that only processes
+    /// the browse's socket
+    #[cfg(feature = "tokio")]
+    pub(super) fn resolve_on_thread(&self) {
+        if let Some((resolver, tx)) = self.manual.borrow_mut().take() {
+            let error_tx = tx.clone();
+            if let Err(e) = resolver_thread(resolver, tx) {
+                let _ = error_tx.send(Err(e));
+            }
+        }
+    }
+
+    /// Waits up to the timeout on the browse & the resolves & TXT queries in progress, with
+    /// manual processing, processing replies that are ready, returning if there were any
+    fn process_manual(&self, timeout: Option<Duration>) -> Result<bool> {
+        self.try_reconnect();
+        let mut manual = self.manual.borrow_mut();
+        let (resolver, tx) = match manual.as_mut() {
+            Some(manual) => manual,
+            None => return Ok(false),
+        };
+        let browsing = self.next_attempt().is_none();
+        let until_attempt = self
+            .next_attempt()
+            .map(|next_attempt| next_attempt.saturating_duration_since(Instant::now()));
+        let timeout = [timeout, resolver.timeout(), until_attempt]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+        let sockets: Vec<Socket> = browsing
+            .then(|| self.socket() as Socket)
+            .into_iter()
+            .chain(resolver.sockets())
+            .collect();
+        let ready = match sockets.is_empty() {
+            // nothing to wait on until it's time to browse again
+            true => {
+                std::thread::sleep(timeout.unwrap_or_default());
+                Vec::new()
+            }
+            false => sockets_ready(&sockets, timeout)?,
+        };
+        let (browse_ready, resolves_ready) = ready.split_at(browsing as usize);
+        if browse_ready.first() == Some(&true) {
+            let r = self.process_result();
+            if r != kDNSServiceErr_NoError {
+                self.lost(r);
+            }
+        }
+        let updates = resolver.process(resolves_ready);
+        // the browser holds the receiver, so sending can't fail
+        for service in resolver.resolve().into_iter().flatten() {
+            let _ = tx.send(service);
+        }
+        for service in updates {
+            let _ = tx.send(Ok(service));
+        }
+        Ok(ready.contains(&true))
+    }
+
+    /// Processes replies that are ready without blocking, returning the services they produced
+    ///
+    /// For event loops watching `sockets`, with `with_manual_processing` browsing & resolving
+    /// are all done here. Otherwise services are resolved on a thread or runtime, so they may
+    /// only be returned by a later call. If browse-only, replies are processed but services are
+    /// received with `recv_discovered_timeout`.
+    pub fn process_ready(&self) -> Vec<Result<Service>> {
+        let mut services = Vec::new();
+        if self.is_manual() {
+            loop {
+                match self.process_manual(Some(Duration::ZERO)) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        services.push(Err(e));
+                        break;
+                    }
+                }
+            }
+        } else if self.shared.is_none() {
+            self.try_reconnect();
+            loop {
+                match self.has_data(Duration::ZERO) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        services.push(Err(e));
+                        break;
+                    }
+                }
+                let r = self.process_result();
+                if r != kDNSServiceErr_NoError {
+                    self.lost(r);
+                    break;
+                }
+            }
+        }
+        services.extend(self.rx.try_iter());
+        services
     }
 
     /// Processes a reply from mDNS service, blocking until there is one
-    fn process_result(&self) -> ffi::DNSServiceErrorType {
+    pub(super) fn process_result(&self) -> ffi::DNSServiceErrorType {
         // shouldn't get here but to be safe for now
-        if self.raw.is_null() {
+        if self.raw.get().is_null() {
             return ffi::kDNSServiceErr_Invalid;
         }
-        unsafe { ffi::DNSServiceProcessResult(self.raw) }
+        unsafe { ffi::DNSServiceProcessResult(self.raw.get()) }
     }
 
     /// returns true if the socket has data and process_result() should be called
-    fn has_data(&self, timeout: Duration) -> Result<bool> {
-        let socket = unsafe { ffi::DNSServiceRefSockFD(self.raw) } as _;
+    pub(super) fn has_data(&self, timeout: Duration) -> Result<bool> {
+        // a runtime with a thread processes its own connection
+        if self.next_attempt().is_some() || self.shared.is_some() || self.raw.get().is_null() {
+            return Ok(false);
+        }
+        let socket = self.socket() as _;
         let r = crate::non_blocking::socket_is_ready(socket, timeout)?;
         Ok(r)
     }
 
-    /// Starts browser with type & optional domain
-    fn start(regtype: String, domain: Option<String>) -> Result<Self> {
-        unsafe {
-            let c_domain: Option<CString>;
-            if let Some(d) = &domain {
-                c_domain = Some(CString::new(d.as_str()).map_err(|_| BrowseError::InvalidString)?);
-            } else {
-                c_domain = None;
-            }
-            let service_type =
-                CString::new(regtype.as_str()).map_err(|_| BrowseError::InvalidString)?;
-            let (tx, rx) = sync_channel::<Result<DiscoveredService>>(10);
-            let tx = Box::into_raw(Box::new(tx));
-            let mut raw: ffi::DNSServiceRef = ptr::null_mut();
-            let r = ffi::DNSServiceBrowse(
-                &mut raw as _,
-                0,
-                0,
-                service_type.as_ptr(),
-                c_domain.map_or(ptr::null_mut(), |d| d.as_ptr()),
-                Some(browse_callback),
-                tx as _,
+    /// Starts browser with type (including any subtype), optional domain & interface index, on
+    /// the runtime if given, resolving on the caller's thread with manual processing
+    fn start(
+        regtype: String,
+        domain: Option<String>,
+        interface_index: u32,
+        runtime: Option<Runtime>,
+        no_resolve: bool,
+        manual_processing: bool,
+    ) -> Result<Self> {
+        let c_domain: Option<CString>;
+        if let Some(d) = &domain {
+            c_domain = Some(
+                CString::new(d.as_str())
+                    .map_err(|_| BrowseError::InvalidString { field: "domain" })?,
             );
-            if r != kDNSServiceErr_NoError {
-                error!("DNSServiceBrowser error: {}", r);
-                return Err(BrowseError::ServiceError(r));
+        } else {
+            c_domain = None;
+        }
+        let service_type = CString::new(regtype.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "regtype" })?;
+        let (tx, rx, rx_waker) = waking_channel::<Result<DiscoveredService>>();
+        let tx = Box::into_raw(Box::new(tx));
+        let (final_tx, final_rx, waker) = waking_channel::<Result<Service>>();
+        // browse-only browsers take services from the callback instead of resolving them
+        let (rx, discovered) = match no_resolve {
+            true => (None, Some(rx)),
+            false => (Some(rx), None),
+        };
+        let mut browser = ServiceBrowser {
+            raw: Cell::new(ptr::null_mut()),
+            rx: final_rx,
+            waker,
+            discovered,
+            browse_only_reported: Cell::new(false),
+            wake: OnceCell::new(),
+            raw_tx: tx,
+            params: BrowseParams {
+                regtype: service_type,
+                domain: c_domain,
+                interface_index,
+            },
+            reconnect: RefCell::new(None),
+            shared: None,
+            manual: RefCell::new(None),
+        };
+        match runtime {
+            Some(runtime) => {
+                let params = browser.params.clone();
+                browser.shared = Some(SharedBrowse::start(runtime, params, tx, rx, final_tx)?);
+            }
+            None => {
+                browser.raw.set(browser.params.browse(None, tx)?);
+                if let Some(rx) = rx {
+                    let resolver = Resolver::new(rx, rx_waker);
+                    match manual_processing {
+                        true => *browser.manual.get_mut() = Some((resolver, final_tx)),
+                        false => resolver_thread(resolver, final_tx)?,
+                    }
+                }
             }
-            let (final_tx, final_rx) = sync_channel::<Result<Service>>(10);
-            resolver_thread(rx, final_tx);
-            Ok(ServiceBrowser {
-                raw,
-                rx: final_rx,
-                raw_tx: tx,
-            })
         }
+        Ok(browser)
+    }
+
+    /// Stops the lost browse & schedules browsing again
+    pub(super) fn lost(&self, error: ffi::DNSServiceErrorType) {
+        error!("Lost daemon connection: {}, browsing again", error);
+        unsafe { ffi::DNSServiceRefDeallocate(self.raw.replace(ptr::null_mut())) };
+        let mut backoff = Backoff::new();
+        let next_attempt = Instant::now() + backoff.next_delay();
+        *self.reconnect.borrow_mut() = Some((backoff, next_attempt));
     }
-    /// Returns discovered services if any
-    pub fn recv_timeout(&self, timeout: Duration) -> Result<Service> {
+
+    /// Time of the next attempt to browse again, if reconnecting
+    pub(super) fn next_attempt(&self) -> Option<Instant> {
+        self.reconnect
+            .borrow()
+            .as_ref()
+            .map(|(_backoff, next_attempt)| *next_attempt)
+    }
+
+    /// Browses again if it's time for the next attempt
+    pub(super) fn try_reconnect(&self) {
+        let mut reconnect = self.reconnect.borrow_mut();
+        let (backoff, next_attempt) = match reconnect.as_mut() {
+            Some(reconnect) => reconnect,
+            None => return,
+        };
+        if Instant::now() < *next_attempt {
+            return;
+        }
+        match self.params.browse(None, self.raw_tx) {
+            Ok(raw) => {
+                info!("Browsing again after {} attempts", backoff.attempt());
+                self.raw.set(raw);
+                *reconnect = None;
+            }
+            Err(e) => {
+                warn!(
+                    "Browsing again failed on attempt {}: {:?}",
+                    backoff.attempt(),
+                    e
+                );
+                *next_attempt = Instant::now() + backoff.next_delay();
+            }
+        }
+    }
+
+    /// Connection status to the daemon, browsing again after it's lost (i.e. daemon restarted)
+    pub fn status(&self) -> ConnectionStatus {
+        if let Some(shared) = &self.shared {
+            return shared.runtime.status();
+        }
+        match self.reconnect.borrow().as_ref() {
+            Some((backoff, _next_attempt)) => ConnectionStatus::Reconnecting {
+                attempt: backoff.attempt(),
+            },
+            None => ConnectionStatus::Connected,
+        }
+    }
+
+    /// Processes replies & waits up to the timeout for a service
+    fn recv_service(&self, timeout: Duration) -> Result<Result<Service>, RecvTimeoutError> {
+        if self.is_manual() {
+            // resolving takes a few replies, so keep processing until there's a service
+            let deadline = Instant::now() + timeout;
+            loop {
+                match self.rx.try_recv() {
+                    Ok(service) => return Ok(service),
+                    Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                    Err(TryRecvError::Empty) => {}
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(RecvTimeoutError::Timeout);
+                }
+                if let Err(e) = self.process_manual(Some(remaining)) {
+                    return Ok(Err(e));
+                }
+            }
+        }
+        self.try_reconnect();
         // TODO: do non-blocking check before calling?
-        if self.has_data(timeout)? {
-            trace!("Data on socket, processing before checking channel");
-            let r = self.process_result();
-            if r != kDNSServiceErr_NoError {
-                return Err(BrowseError::ServiceError(r));
+        match self.has_data(timeout) {
+            Ok(true) => {
+                trace!("Data on socket, processing before checking channel");
+                let r = self.process_result();
+                if r != kDNSServiceErr_NoError {
+                    self.lost(r);
+                }
             }
+            Ok(false) => {}
+            Err(e) => return Ok(Err(e)),
+        }
+        self.rx.recv_timeout(timeout)
+    }
+
+    /// Returns a discovered service if any by the timeout, none if there wasn't one yet
+    ///
+    /// Fails with `Error::BrowseOnly` if browsing with `with_no_resolve`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Service>> {
+        if self.browse_only() {
+            return Err(BrowseError::BrowseOnly);
+        }
+        match self.recv_service(timeout) {
+            Ok(service) => service.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(BrowseError::Stopped),
         }
+    }
 
-        match self.rx.recv_timeout(timeout) {
-            Ok(service_result) => service_result,
-            Err(RecvTimeoutError::Timeout) => Err(BrowseError::Timeout),
-            Err(RecvTimeoutError::Disconnected) => Err(BrowseError::IoError(IoError::from(
-                ErrorKind::ConnectionReset,
-            ))),
+    /// Socket waking `next_service` as services are sent on, made the first time it's needed
+    fn wake_socket(&self) -> Result<Arc<WakeSocket>> {
+        if let Some(wake) = self.wake.get() {
+            return Ok(wake.clone());
+        }
+        let wake = Arc::new(WakeSocket::new()?);
+        Ok(self.wake.get_or_init(|| wake).clone())
+    }
+
+    /// True if browsing with `with_no_resolve`, finding services unresolved
+    pub(crate) fn browse_only(&self) -> bool {
+        self.discovered.is_some()
+    }
+
+    /// Processes replies until a service is found, waiting up to the timeout if given, none if
+    /// there wasn't one by then
+    ///
+    /// Services are found unresolved if browse-only, otherwise they're found resolved.
+    pub(crate) fn next_discovered(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DiscoveredService>> {
+        let discovered = match &self.discovered {
+            Some(discovered) => discovered,
+            None => {
+                let service = match timeout {
+                    Some(timeout) => self.recv_timeout(timeout)?,
+                    None => Some(self.next_service().unwrap_or(Err(BrowseError::Stopped))?),
+                };
+                return Ok(service.map(|service| service.discovered()));
+            }
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut timed_out = false;
+        loop {
+            match discovered.try_recv() {
+                Ok(service) => return service.map(Some),
+                Err(TryRecvError::Disconnected) => return Err(BrowseError::Stopped),
+                Err(TryRecvError::Empty) => {}
+            }
+            if timed_out {
+                return Ok(None);
+            }
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            // processes what's ready once more at the deadline, then gives up
+            timed_out = remaining == Some(Duration::ZERO);
+            if self.shared.is_some() {
+                // the runtime's thread processes replies & sends services on
+                let service = match remaining {
+                    Some(remaining) => match discovered.recv_timeout(remaining) {
+                        Ok(service) => service,
+                        Err(RecvTimeoutError::Timeout) => return Ok(None),
+                        Err(RecvTimeoutError::Disconnected) => return Err(BrowseError::Stopped),
+                    },
+                    None => discovered.recv().map_err(|_| BrowseError::Stopped)?,
+                };
+                return service.map(Some);
+            } else {
+                self.try_reconnect();
+                match self.next_attempt() {
+                    Some(next_attempt) => {
+                        let until_attempt = next_attempt.saturating_duration_since(Instant::now());
+                        std::thread::sleep(
+                            remaining
+                                .map_or(until_attempt, |remaining| remaining.min(until_attempt)),
+                        );
+                    }
+                    None => {
+                        if sockets_ready(&[self.socket() as _], remaining)?[0] {
+                            let r = self.process_result();
+                            if r != kDNSServiceErr_NoError {
+                                self.lost(r);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blocks until a service is found, none once the browser's stopped
+    ///
+    /// Fails with `Error::BrowseOnly` once if browse-only, then returns none.
+    pub(crate) fn next_service(&self) -> Option<Result<Service>> {
+        if self.browse_only() {
+            return (!self.browse_only_reported.replace(true))
+                .then_some(Err(BrowseError::BrowseOnly));
+        }
+        if self.is_manual() {
+            // resolving takes a few replies, so keep processing until there's a service
+            loop {
+                match self.rx.try_recv() {
+                    Ok(service) => return Some(service),
+                    Err(TryRecvError::Disconnected) => return None,
+                    Err(TryRecvError::Empty) => {}
+                }
+                if let Err(e) = self.process_manual(None) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        if self.shared.is_some() {
+            // the runtime's thread processes replies & sends services on
+            return self.rx.recv().ok();
+        }
+        let wake = match self.wake_socket() {
+            Ok(wake) => wake,
+            Err(e) => return Some(Err(e)),
+        };
+        // woken as the resolver thread sends services on, or once it's stopped
+        let waker = Waker::from(wake.clone());
+        loop {
+            // registered first, so a service sent after checking still wakes the socket
+            self.waker.register(&waker);
+            match self.rx.try_recv() {
+                Ok(service) => return Some(service),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => {}
+            }
+            self.try_reconnect();
+            let ready = match self.next_attempt() {
+                Some(next_attempt) => sockets_ready(
+                    &[wake.socket()],
+                    Some(next_attempt.saturating_duration_since(Instant::now())),
+                ),
+                None => sockets_ready(&[wake.socket(), self.socket() as _], None),
+            };
+            let ready = match ready {
+                Ok(ready) => ready,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if ready[0] {
+                wake.drain();
+            }
+            if ready.get(1) == Some(&true) {
+                let r = self.process_result();
+                if r != kDNSServiceErr_NoError {
+                    self.lost(r);
+                }
+            }
         }
     }
 }
@@ -285,7 +1331,12 @@ impl Drop for ServiceBrowser {
     fn drop(&mut self) {
         unsafe {
             // ensure we cancel browser first by deallocating it...
-            ffi::DNSServiceRefDeallocate(self.raw);
+            if let Some(shared) = &self.shared {
+                shared.stop();
+            }
+            if !self.raw.get().is_null() {
+                ffi::DNSServiceRefDeallocate(self.raw.get());
+            }
             // then we should be able to safely drop the sender which will signal resolver thread to exit
             let _tx = Box::from_raw(self.raw_tx);
         }
@@ -295,56 +1346,131 @@ impl Drop for ServiceBrowser {
 // should be safe to send across threads, just not shared
 unsafe impl Send for ServiceBrowser {}
 
-pub fn browse(builder: ServiceBrowserBuilder) -> Result<ServiceBrowser> {
-    ServiceBrowser::start(builder.regtype, builder.domain)
+#[cfg(not(target_os = "windows"))]
+impl std::os::unix::io::AsRawFd for ServiceBrowser {
+    /// Socket to watch for replies to process with `process_ready`, as `socket`
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.socket()
+    }
 }
-macro_rules! mut_void_ptr {
-    ($var:expr) => {
-        $var as *mut _ as *mut c_void
-    };
+#[cfg(target_os = "windows")]
+impl std::os::windows::io::AsRawSocket for ServiceBrowser {
+    /// Socket to watch for replies to process with `process_ready`, as `socket`
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.socket() as _
+    }
+}
+
+pub fn browse(builder: ServiceBrowserBuilder) -> Result<ServiceBrowser> {
+    let subtypes: Vec<String> = builder.subtype.into_iter().collect();
+    let regtype =
+        regtype_with_subtypes(&builder.regtype, &subtypes).map_err(BrowseError::InvalidSubtype)?;
+    let interface_index =
+        interface_index(builder.interface.as_ref()).map_err(BrowseError::UnknownInterface)?;
+    ServiceBrowser::start(
+        regtype,
+        builder.domain,
+        interface_index,
+        builder.runtime,
+        builder.no_resolve,
+        builder.manual_processing,
+    )
 }
 impl DiscoveredService {
-    fn resolve(&self) -> Result<Vec<ResolvedService>> {
-        let mut sdref: ffi::DNSServiceRef = unsafe { std::mem::zeroed() };
-        let regtype =
-            CString::new(self.regtype.as_str()).map_err(|_| BrowseError::InvalidString)?;
-        let name = CString::new(self.name.as_str()).map_err(|_| BrowseError::InvalidString)?;
-        let domain = CString::new(self.domain.as_str()).map_err(|_| BrowseError::InvalidString)?;
-        let mut pending_resolution: PendingResolution = Default::default();
-        unsafe {
-            let r = ffi::DNSServiceResolve(
-                &mut sdref,
-                0,
-                self.interface_index,
-                name.as_ptr(),
-                regtype.as_ptr(),
-                domain.as_ptr(),
-                Some(resolve_callback),
-                mut_void_ptr!(&mut pending_resolution),
-            );
-            if r != kDNSServiceErr_NoError {
-                return Err(BrowseError::ServiceError(r));
+    /// Starts resolving, on the runtime's connection if given, returning the new DNSServiceRef
+    ///
+    /// # Safety
+    /// `context` must outlive the returned ref
+    unsafe fn start_resolve(
+        &self,
+        connection: Option<&Connection>,
+        context: *mut PendingResolution,
+    ) -> Result<ffi::DNSServiceRef> {
+        let regtype = CString::new(self.regtype.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "regtype" })?;
+        let name = CString::new(self.name.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "name" })?;
+        let domain = CString::new(self.domain.as_str())
+            .map_err(|_| BrowseError::InvalidString { field: "domain" })?;
+        let (mut sdref, flags) = initial_ref(connection).map_err(BrowseError::service)?;
+        let r = ffi::DNSServiceResolve(
+            &mut sdref,
+            flags,
+            self.interface_index,
+            name.as_ptr(),
+            regtype.as_ptr(),
+            domain.as_ptr(),
+            Some(resolve_callback),
+            mut_void_ptr!(context),
+        );
+        if r != kDNSServiceErr_NoError {
+            return Err(BrowseError::service(r));
+        }
+        Ok(sdref)
+    }
+    /// Resolves on its own ref, waiting up to the timeout if given, none if not resolved by then
+    fn resolve_records(&self, timeout: Option<Duration>) -> Result<Option<PendingResolution>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut context = Box::<PendingResolution>::default();
+        let sdref = unsafe { self.start_resolve(None, context.as_mut())? };
+        let result = loop {
+            if !context.more_coming {
+                break Ok(true);
+            }
+            if let Some(deadline) = deadline {
+                let socket = unsafe { ffi::DNSServiceRefSockFD(sdref) } as _;
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match crate::non_blocking::sockets_ready(&[socket], Some(remaining)) {
+                    Ok(ready) if ready[0] => {}
+                    Ok(_ready) => break Ok(false),
+                    Err(e) => break Err(e.into()),
+                }
             }
-            #[allow(clippy::while_immutable_condition)]
-            while pending_resolution.more_coming {
-                ffi::DNSServiceProcessResult(sdref);
+            let r = unsafe { ffi::DNSServiceProcessResult(sdref) };
+            if r != kDNSServiceErr_NoError {
+                break Err(BrowseError::service(r));
             }
-            ffi::DNSServiceRefDeallocate(sdref);
+        };
+        unsafe { ffi::DNSServiceRefDeallocate(sdref) };
+        match result {
+            Ok(true) => Ok(Some(*context)),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e),
         }
+    }
+}
 
-        Ok(pending_resolution.results)
+/// Resolves a discovered service, waiting up to the timeout if given, none if not resolved by
+/// then
+pub fn resolve(
+    discovered: &DiscoveredService,
+    timeout: Option<Duration>,
+) -> Result<Option<Service>> {
+    let resolution = match discovered.resolve_records(timeout)? {
+        Some(resolution) => resolution,
+        None => return Ok(None),
+    };
+    match resolution.error {
+        Some(e) if resolution.results.is_empty() => Err(BrowseError::service(e)),
+        _ => Ok(Some(service_from_resolved(
+            discovered.clone(),
+            resolution.results,
+        ))),
     }
 }
 
 struct PendingResolution {
     more_coming: bool,
     results: Vec<ResolvedService>,
+    /// Error the daemon replied with, if any
+    error: Option<ffi::DNSServiceErrorType>,
 }
 impl Default for PendingResolution {
     fn default() -> Self {
         PendingResolution {
             more_coming: true, // default to true, just as a way to say yes for first entry
             results: Vec::with_capacity(1),
+            error: None,
         }
     }
 }
@@ -352,14 +1478,14 @@ impl Default for PendingResolution {
 /// Resolved service information, name, hostname, port, & TXT record if any
 #[derive(Debug)]
 pub struct ResolvedService {
-    /// Full name of service
+    /// Full name of service, escaped as dns_sd reports it, i.e. `My\032Printer._ipp._tcp.local.`
     pub full_name: String,
     /// Hostname of service, usable with gethostbyname()
     pub hostname: String,
     /// Port service is on
     pub port: u16,
     /// TXT record service has if any
-    pub txt_record: Option<HashMap<String, String>>,
+    pub txt_record: Option<TXTRecord>,
 }
 impl ToSocketAddrs for ResolvedService {
     type Iter = std::vec::IntoIter<SocketAddr>;
@@ -385,26 +1511,28 @@ unsafe extern "C" fn resolve_callback(
     if error_code != kDNSServiceErr_NoError {
         error!("Error resolving service: {}", error_code);
         context.more_coming = false;
+        context.error = Some(error_code);
         return;
     }
     // flag if we have more records coming so we can fetch them before stopping resolution
-    context.more_coming = flags & ffi::kDNSServiceFlagsMoreComing as u32 != 0;
+    context.more_coming = flags & ffi::kDNSServiceFlagsMoreComing != 0;
+    // kept as reported, as legacy service types wouldn't parse as a ServiceFullName
     let process = || -> Result<(String, String)> {
         let c_str: &CStr = CStr::from_ptr(full_name);
         let full_name: &str = c_str
             .to_str()
-            .map_err(|_| BrowseError::InternalInvalidString)?;
+            .map_err(|_| BrowseError::InternalInvalidString { field: "full name" })?;
         let c_str: &CStr = CStr::from_ptr(host_target);
         let hostname: &str = c_str
             .to_str()
-            .map_err(|_| BrowseError::InternalInvalidString)?;
+            .map_err(|_| BrowseError::InternalInvalidString { field: "hostname" })?;
         Ok((full_name.to_owned(), hostname.to_owned()))
     };
     let txt_record = if txt_len > 0 {
         let data = std::slice::from_raw_parts(txt_record, txt_len as usize);
-        match hash_from_txt(data) {
-            Ok(hash) if !hash.is_empty() => Some(hash),
-            Ok(_hash) => None,
+        match TXTRecord::from_bytes(data) {
+            Ok(txt) if !txt.is_empty() => Some(txt),
+            Ok(_txt) => None,
             Err(e) => {
                 error!("Failed to get TXT record: {:?}", e);
                 None
@@ -428,50 +1556,3 @@ unsafe extern "C" fn resolve_callback(
         }
     }
 }
-
-fn hash_from_txt(data: &[u8]) -> Result<HashMap<String, String>> {
-    let slice = data;
-    let txt_len = slice.len() as u16;
-    let txt_bytes = slice.as_ptr() as *const c_void;
-
-    unsafe {
-        let total_keys = ffi::TXTRecordGetCount(txt_len, txt_bytes);
-        let mut hash: HashMap<String, String> = HashMap::with_capacity(total_keys as _);
-        for i in 0..total_keys {
-            // index is u16 so we can't go over u16::max_value() but likely will end before that
-            let mut key: [c_char; 256] = std::mem::zeroed();
-            let mut value = std::mem::zeroed();
-            let mut value_len: u8 = 0;
-            let err = ffi::TXTRecordGetItemAtIndex(
-                txt_len,
-                txt_bytes,
-                i,
-                key.len() as u16,
-                key.as_mut_ptr(),
-                &mut value_len,
-                &mut value,
-            );
-            if err == kDNSServiceErr_NoError {
-                let c_str: &CStr = CStr::from_ptr(key.as_ptr());
-                let key: &str = c_str.to_str().unwrap();
-                let data = std::slice::from_raw_parts(value as *mut u8, value_len as _);
-                match std::str::from_utf8(data) {
-                    Ok(value) if !key.is_empty() && !value.is_empty() => {
-                        hash.insert(key.to_owned(), value.to_owned());
-                    }
-                    Ok(_value) => {
-                        trace!("Discarding TXT key with empty key & value");
-                    }
-                    Err(e) => {
-                        error!("Error processing TXT value as UTF-8: {}", e);
-                    }
-                }
-            }
-            if err == ffi::kDNSServiceErr_Invalid {
-                error!("Error invalid fetching TXT");
-                break;
-            }
-        }
-        Ok(hash)
-    }
-}
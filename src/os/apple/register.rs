@@ -1,35 +1,273 @@
 //! Registration of dns-sd services
 
-// use super::txt::TXTRecord;
+use crate::error::{RegistrationError, ServiceErrorKind};
 use crate::ffi::apple::{
-    kDNSServiceErr_NoError, DNSServiceErrorType, DNSServiceFlags, DNSServiceProcessResult,
-    DNSServiceRef, DNSServiceRefDeallocate, DNSServiceRefSockFD, DNSServiceRegister,
+    kDNSServiceClass_IN, kDNSServiceErr_BadReference, kDNSServiceErr_NameConflict,
+    kDNSServiceErr_NoError, kDNSServiceErr_Unknown, kDNSServiceFlagsNoAutoRename,
+    kDNSServiceFlagsUnique, kDNSServiceType_A, kDNSServiceType_AAAA, DNSRecordRef,
+    DNSServiceAddRecord, DNSServiceCreateConnection, DNSServiceErrorType, DNSServiceFlags,
+    DNSServiceProcessResult, DNSServiceRef, DNSServiceRefDeallocate, DNSServiceRefSockFD,
+    DNSServiceRegister, DNSServiceRegisterRecord, DNSServiceRemoveRecord, DNSServiceUpdateRecord,
 };
-use crate::os::apple::txt::TXTRecord;
+use crate::interface::interface_index;
+#[cfg(feature = "async-io")]
+use crate::non_blocking::{recv_timeout_async, socket_readable_async};
+use crate::non_blocking::{
+    socket_is_ready, sockets_ready, waking_channel, ChannelWaker, Socket, WakingSender,
+};
+use crate::os::apple::runtime::{initial_ref, lock, Connection, Operation, Runtime};
+use crate::record::{RecordData, RecordError};
+use crate::register::{encode_txt, event_channel, EventSender, RegistrationEvent, RenameStrategy};
+use crate::status::{Backoff, ConnectionStatus};
+use crate::subtype::regtype_with_subtypes;
+use crate::txt::TXTRecord;
 use crate::{register::Result, DNSServiceBuilder};
 use std::ffi::{c_void, CStr, CString};
 use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::IpAddr;
 use std::os::raw::c_char;
 use std::ptr;
 use std::ptr::null_mut;
-use std::sync::mpsc::{sync_channel, SyncSender};
-use std::time::Duration;
-use thiserror::Error;
-
-const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
-
-/// Common error for DNS-SD service
-#[derive(Debug, Error, Copy, Clone, PartialEq, Eq)]
-pub enum RegistrationError {
-    /// Invalid input string
-    #[error("Invalid string argument, must be C string compatible")]
-    InvalidString,
-    /// Unexpected invalid strings from C API
-    #[error("DNSSD API returned invalid UTF-8 string")]
-    InternalInvalidString,
-    /// Error from DNSSD service
-    #[error("DNSSD Error: {0}")]
-    ServiceError(i32),
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long the registration thread waits for replies before checking if it's stopped
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Gives up renaming after this many attempts, in case the rename strategy keeps conflicting
+const MAX_RENAME_ATTEMPTS: u32 = 32;
+
+/// State shared with the register callback
+struct RegisterContext {
+    /// Sends the first reply back to `register_service`
+    tx: WakingSender<Result<DNSServiceRegisterReply>>,
+    /// How long to wait for the daemon, when registering host records again
+    timeout: Duration,
+    /// Latest successful reply, updated if the service is renamed after a conflict
+    reply: Mutex<Option<DNSServiceRegisterReply>>,
+    /// Service type, including subtypes
+    regtype: CString,
+    domain: Option<CString>,
+    host: Option<CString>,
+    interface_index: u32,
+    port: u16,
+    flags: DNSServiceFlags,
+    /// Current TXT record, to register again with if renamed
+    txt: Mutex<Option<Vec<u8>>>,
+    rename: Option<RenameStrategy>,
+    /// Name requested, or the first conflicting one if left to the system
+    base_name: Mutex<Option<String>>,
+    /// Name to register again with, set by the callback after a conflict
+    pending_rename: Mutex<Option<String>>,
+    /// Attempts since last registered, counting the original name, so later conflicts rename again
+    rename_attempts: AtomicU32,
+    /// Additional records, to add again if registered again after a rename
+    records: Mutex<Vec<AddedRecord>>,
+    next_record_id: AtomicU64,
+    /// Set once the service is dropped, so it isn't registered again
+    stopped: AtomicBool,
+    events: EventSender,
+    status: Mutex<ConnectionStatus>,
+    /// Set while registering again after losing the daemon, to report it once registered
+    reregistering: AtomicBool,
+    host_addresses: Vec<IpAddr>,
+    /// Registered again along with the service after losing the daemon
+    host_records: Mutex<Option<HostRecords>>,
+}
+impl RegisterContext {
+    /// Sends a reply on to `register_service`, nobody is waiting for replies after the first
+    fn send(&self, reply: Result<DNSServiceRegisterReply>) {
+        if let Err(e) = self.tx.send(reply) {
+            trace!("Registration reply not sent: {:?}", e);
+        }
+    }
+    /// Handles a name conflict, picking a new name with the rename strategy if there is one
+    fn conflict(&self, name: String) {
+        self.events
+            .send(RegistrationEvent::Conflict { name: name.clone() });
+        let rename = match &self.rename {
+            Some(rename) => rename,
+            None => {
+                error!("Service name {} conflicts with another service", name);
+                self.send(Err(RegistrationError::service(kDNSServiceErr_NameConflict)));
+                return;
+            }
+        };
+        let attempt = self.rename_attempts.fetch_add(1, Ordering::SeqCst) + 1;
+        if attempt > MAX_RENAME_ATTEMPTS {
+            error!("Service name {} conflicts, giving up renaming", name);
+            self.send(Err(RegistrationError::service(kDNSServiceErr_NameConflict)));
+            return;
+        }
+        let base_name = lock(&self.base_name).get_or_insert(name).clone();
+        let new_name = rename(&base_name, attempt);
+        info!("Service name conflict, renaming to {}", new_name);
+        *lock(&self.pending_rename) = Some(new_name);
+    }
+    /// Registers the service, on the runtime's connection if given, returning the new
+    /// DNSServiceRef
+    ///
+    /// # Safety
+    /// `context` must point to this context & outlive the returned ref
+    unsafe fn register(
+        &self,
+        context: *mut RegisterContext,
+        name: Option<&str>,
+        connection: Option<&Connection>,
+    ) -> Result<DNSServiceRef> {
+        let c_name = match name {
+            Some(name) => Some(
+                CString::new(name)
+                    .map_err(|_| RegistrationError::InvalidString { field: "name" })?,
+            ),
+            None => None,
+        };
+        let txt = lock(&self.txt);
+        let (txt_record, txt_len) = match txt.as_ref() {
+            Some(txt) => (txt.as_ptr() as *const c_void, txt.len() as u16),
+            None => (ptr::null(), 0),
+        };
+        let (mut raw, share_flags) = initial_ref(connection).map_err(RegistrationError::service)?;
+        let result = DNSServiceRegister(
+            &mut raw,
+            self.flags | share_flags,
+            self.interface_index,
+            c_name.as_ref().map_or(null_mut(), |c| c.as_ptr()),
+            self.regtype.as_ptr(),
+            self.domain.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
+            self.host.as_ref().map_or(ptr::null(), |h| h.as_ptr()),
+            self.port.to_be(),
+            txt_len,
+            txt_record,
+            Some(register_reply),
+            context as _,
+        );
+        if result == kDNSServiceErr_NoError {
+            Ok(raw)
+        } else {
+            Err(RegistrationError::service(result))
+        }
+    }
+}
+
+/// Additional record added to the service
+struct AddedRecord {
+    id: u64,
+    raw: DNSRecordRef,
+    rrtype: u16,
+    data: Vec<u8>,
+    ttl: u32,
+}
+
+/// Adds a record to the service ref, returning its ref
+///
+/// # Safety
+/// `service` must be a valid registration ref
+unsafe fn add_record(
+    service: DNSServiceRef,
+    rrtype: u16,
+    data: &[u8],
+    ttl: u32,
+) -> Result<DNSRecordRef> {
+    let mut raw: DNSRecordRef = null_mut();
+    let r = DNSServiceAddRecord(
+        service,
+        &mut raw,
+        0,
+        rrtype,
+        data.len() as u16,
+        data.as_ptr() as *const c_void,
+        ttl,
+    );
+    if r != kDNSServiceErr_NoError {
+        error!("Error adding record of type {}: {}", rrtype, r);
+        return Err(RegistrationError::service_or_unsupported(
+            r,
+            "Adding records",
+        ));
+    }
+    Ok(raw)
+}
+
+/// Handle to an additional record on a registered service, to update or remove it
+///
+/// The record stays registered with the service if the handle is dropped.
+pub struct RecordHandle {
+    service: Arc<ServiceRef>,
+    id: u64,
+    rrtype: u16,
+}
+impl fmt::Debug for RecordHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RecordHandle {{ id: {}, rrtype: {} }}",
+            self.id, self.rrtype
+        )
+    }
+}
+impl RecordHandle {
+    /// Replaces the record's data & TTL (0 for the default), data must be of the same type
+    pub fn update(&self, data: &RecordData, ttl: u32) -> Result<()> {
+        if data.rrtype() != self.rrtype {
+            return Err(RecordError::TypeMismatch {
+                expected: self.rrtype,
+                found: data.rrtype(),
+            }
+            .into());
+        }
+        let data = data.to_bytes()?;
+        let _connection = self.service.connection();
+        let raw = self.service.registered_raw()?;
+        let mut records = lock(&self.service.context().records);
+        let record = records
+            .iter_mut()
+            .find(|record| record.id == self.id)
+            .ok_or(RegistrationError::RecordRemoved)?;
+        let r = unsafe {
+            DNSServiceUpdateRecord(
+                *raw,
+                record.raw,
+                0,
+                data.len() as u16,
+                data.as_ptr() as *const c_void,
+                ttl,
+            )
+        };
+        if r != kDNSServiceErr_NoError {
+            error!("Error updating record: {}", r);
+            return Err(RegistrationError::service_or_unsupported(
+                r,
+                "Updating records",
+            ));
+        }
+        record.data = data;
+        record.ttl = ttl;
+        Ok(())
+    }
+    /// Removes the record from the service
+    pub fn remove(self) -> Result<()> {
+        let _connection = self.service.connection();
+        let raw = self.service.registered_raw()?;
+        let mut records = lock(&self.service.context().records);
+        let index = records
+            .iter()
+            .position(|record| record.id == self.id)
+            .ok_or(RegistrationError::RecordRemoved)?;
+        let record = records.remove(index);
+        let r = unsafe { DNSServiceRemoveRecord(*raw, record.raw, 0) };
+        if r != kDNSServiceErr_NoError {
+            error!("Error removing record: {}", r);
+            return Err(RegistrationError::service_or_unsupported(
+                r,
+                "Removing records",
+            ));
+        }
+        Ok(())
+    }
 }
 
 unsafe extern "C" fn register_reply(
@@ -42,20 +280,19 @@ unsafe extern "C" fn register_reply(
     context: *mut c_void,
 ) {
     info!("Got reply");
-    // let context: &mut RegisteredDnsService = &mut *(context as *mut RegisteredDnsService);
     let process = || -> Result<(String, String, String)> {
         let c_str: &CStr = CStr::from_ptr(name);
         let service_name: &str = c_str
             .to_str()
-            .map_err(|_| RegistrationError::InternalInvalidString)?;
+            .map_err(|_| RegistrationError::InternalInvalidString { field: "name" })?;
         let c_str: &CStr = CStr::from_ptr(regtype);
         let regtype: &str = c_str
             .to_str()
-            .map_err(|_| RegistrationError::InternalInvalidString)?;
+            .map_err(|_| RegistrationError::InternalInvalidString { field: "regtype" })?;
         let c_str: &CStr = CStr::from_ptr(domain);
         let reply_domain: &str = c_str
             .to_str()
-            .map_err(|_| RegistrationError::InternalInvalidString)?;
+            .map_err(|_| RegistrationError::InternalInvalidString { field: "domain" })?;
         Ok((
             service_name.to_owned(),
             regtype.to_owned(),
@@ -63,8 +300,7 @@ unsafe extern "C" fn register_reply(
         ))
     };
     if !context.is_null() {
-        let tx_ptr: *mut SyncSender<Result<DNSServiceRegisterReply>> = context as _;
-        let tx = &*tx_ptr;
+        let context = &*(context as *const RegisterContext);
         trace!("Registration replied");
         match process() {
             Ok((name, regtype, domain)) => {
@@ -74,34 +310,214 @@ unsafe extern "C" fn register_reply(
                         name,
                         domain,
                     };
-                    tx.send(Ok(reply)).unwrap();
+                    lock(&context.base_name).get_or_insert_with(|| reply.name.clone());
+                    context.rename_attempts.store(1, Ordering::SeqCst);
+                    let previous = lock(&context.reply).replace(reply.clone());
+                    *lock(&context.status) = ConnectionStatus::Connected;
+                    match previous {
+                        _ if context.reregistering.swap(false, Ordering::SeqCst) => {
+                            context.events.send(RegistrationEvent::Reregistered {
+                                name: reply.name.clone(),
+                            })
+                        }
+                        None => context.events.send(RegistrationEvent::Registered {
+                            name: reply.name.clone(),
+                        }),
+                        Some(previous) if previous.name != reply.name => {
+                            context.events.send(RegistrationEvent::Renamed {
+                                from: previous.name,
+                                to: reply.name.clone(),
+                            })
+                        }
+                        Some(_previous) => {}
+                    }
+                    context.send(Ok(reply));
                     info!("Reply info sent");
+                } else if error_code == kDNSServiceErr_NameConflict {
+                    context.conflict(name);
                 } else {
                     error!("Error in reply: {}", error_code);
-                    tx.send(Err(RegistrationError::ServiceError(error_code)))
-                        .unwrap();
+                    context.send(Err(RegistrationError::service(error_code)));
                 }
             }
             Err(e) => {
                 error!("Error in reply: {:?}", e);
-                tx.send(Err(e)).unwrap();
+                context.send(Err(e));
             }
         }
     }
 }
 
+/// Replies still expected for host records being registered
+struct PendingRecords {
+    remaining: usize,
+    error: Option<DNSServiceErrorType>,
+}
+
+unsafe extern "C" fn register_record_reply(
+    _sd_ref: DNSServiceRef,
+    _record: DNSRecordRef,
+    _flags: DNSServiceFlags,
+    error_code: DNSServiceErrorType,
+    context: *mut c_void,
+) {
+    let context: &mut PendingRecords = &mut *(context as *mut PendingRecords);
+    context.remaining = context.remaining.saturating_sub(1);
+    if error_code != kDNSServiceErr_NoError {
+        error!("Error registering host record: {}", error_code);
+        context.error.get_or_insert(error_code);
+    }
+}
+
+/// A & AAAA records registered on their own connection for a custom host, processed along with
+/// the service & removed on drop
+struct HostRecords {
+    raw: DNSServiceRef,
+    context: Box<PendingRecords>,
+}
+impl HostRecords {
+    /// Starts registering records for each address, confirmed as replies are processed
+    fn start(host: &CStr, addresses: &[IpAddr], interface_index: u32) -> Result<Self> {
+        let mut raw: DNSServiceRef = null_mut();
+        let r = unsafe { DNSServiceCreateConnection(&mut raw) };
+        if r != kDNSServiceErr_NoError {
+            return Err(RegistrationError::service_or_unsupported(
+                r,
+                "Registering host records",
+            ));
+        }
+        let mut records = HostRecords {
+            raw,
+            context: Box::new(PendingRecords {
+                remaining: addresses.len(),
+                error: None,
+            }),
+        };
+        for address in addresses {
+            let (rrtype, data) = match address {
+                IpAddr::V4(ip) => (kDNSServiceType_A, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (kDNSServiceType_AAAA, ip.octets().to_vec()),
+            };
+            let mut record: DNSRecordRef = null_mut();
+            let r = unsafe {
+                DNSServiceRegisterRecord(
+                    records.raw,
+                    &mut record,
+                    kDNSServiceFlagsUnique,
+                    interface_index,
+                    host.as_ptr(),
+                    rrtype as u16,
+                    kDNSServiceClass_IN as u16,
+                    data.len() as u16,
+                    data.as_ptr() as *const c_void,
+                    0, // default TTL
+                    Some(register_record_reply),
+                    records.context.as_mut() as *mut PendingRecords as *mut c_void,
+                )
+            };
+            if r != kDNSServiceErr_NoError {
+                error!("Error registering {} for {:?}: {}", address, host, r);
+                return Err(RegistrationError::service_or_unsupported(
+                    r,
+                    "Registering host records",
+                ));
+            }
+        }
+        Ok(records)
+    }
+    /// True while records are waiting to be confirmed
+    fn pending(&self) -> bool {
+        self.context.remaining > 0 && self.context.error.is_none()
+    }
+    fn socket(&self) -> i32 {
+        unsafe { DNSServiceRefSockFD(self.raw) }
+    }
+    fn process(&mut self) -> Result<()> {
+        let r = unsafe { DNSServiceProcessResult(self.raw) };
+        if r != kDNSServiceErr_NoError {
+            return Err(RegistrationError::service(r));
+        }
+        Ok(())
+    }
+    /// Fails if any record wasn't confirmed
+    fn finish(self, host: &CStr) -> Result<Self> {
+        match self.context.error {
+            Some(e) => {
+                error!("Registering records for {:?} failed: {}", host, e);
+                Err(RegistrationError::service(e))
+            }
+            None => Ok(self),
+        }
+    }
+    /// Registers records for each address, waiting until they've all been confirmed
+    fn register(
+        host: &CStr,
+        addresses: &[IpAddr],
+        interface_index: u32,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut records = HostRecords::start(host, addresses, interface_index)?;
+        let deadline = Instant::now() + timeout;
+        while records.pending() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !sockets_ready(&[records.socket() as _], Some(remaining))?[0] {
+                error!("Timed out waiting for host records to register");
+                return Err(RegistrationError::Timeout);
+            }
+            records.process()?;
+        }
+        records.finish(host)
+    }
+    /// Registers records for each address, waiting without blocking until they've all been
+    /// confirmed
+    #[cfg(feature = "async-io")]
+    async fn register_async(
+        host: &CStr,
+        addresses: &[IpAddr],
+        interface_index: u32,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut records = HostRecords::start(host, addresses, interface_index)?;
+        let deadline = Instant::now() + timeout;
+        while records.pending() {
+            if !socket_readable_async(records.socket(), deadline).await? {
+                error!("Timed out waiting for host records to register");
+                return Err(RegistrationError::Timeout);
+            }
+            records.process()?;
+        }
+        records.finish(host)
+    }
+}
+impl Drop for HostRecords {
+    fn drop(&mut self) {
+        trace!("Deallocating host records connection");
+        unsafe { DNSServiceRefDeallocate(self.raw) };
+    }
+}
+unsafe impl Send for HostRecords {}
+
 /// DNS-SD Service for registration use
 pub struct RegisteredDnsService {
-    socket: i32,
+    service: Arc<ServiceRef>,
+    txt_size_limit: Option<usize>,
+    /// Thread processing replies until unregistered, unless registered on a runtime or with
+    /// manual processing
+    thread: Option<JoinHandle<Result<()>>>,
+    events: Option<Receiver<RegistrationEvent>>,
+    /// Replies to the service's own ref are processed by `process_ready`
+    manual_processing: bool,
+    /// Backoff & time of the next attempt while reconnecting, with manual processing
+    reconnect: Option<(Backoff, Instant)>,
 }
 impl fmt::Debug for RegisteredDnsService {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "RegisteredDnsService {{ socket: {} }}", self.socket)
+        write!(f, "RegisteredDnsService {{ socket: {} }}", self.socket())
     }
 }
 
 /// Reply information upon successful registration
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DNSServiceRegisterReply {
     /// Service type of successfully registered service
     pub regtype: String,
@@ -112,107 +528,799 @@ pub struct DNSServiceRegisterReply {
 }
 
 /// Service ref to encapsulate DNSServiceRef to send to a thread & cleanup on drop
+///
+/// The DNSServiceRef is replaced when the service is registered again under a new name.
 struct ServiceRef {
-    raw: DNSServiceRef,
-    context: *mut c_void,
+    raw: Mutex<DNSServiceRef>,
+    context: *mut RegisterContext,
+    /// Runtime processing replies on its shared connection, if registered on one
+    runtime: Option<Runtime>,
 }
 impl ServiceRef {
-    fn new(raw: DNSServiceRef, context: *mut c_void) -> Self {
-        ServiceRef { raw, context }
+    fn new(raw: DNSServiceRef, context: *mut RegisterContext, runtime: Option<Runtime>) -> Self {
+        ServiceRef {
+            raw: Mutex::new(raw),
+            context,
+            runtime,
+        }
+    }
+    /// Locks the runtime's connection if registered on one, before calling into dns_sd
+    fn connection(&self) -> Option<MutexGuard<'_, Connection>> {
+        self.runtime.as_ref().map(Runtime::connection)
+    }
+    fn raw(&self) -> DNSServiceRef {
+        *lock(&self.raw)
+    }
+    /// Current DNSServiceRef, failing if the service was unregistered
+    fn registered_raw(&self) -> Result<MutexGuard<'_, DNSServiceRef>> {
+        let raw = lock(&self.raw);
+        if raw.is_null() {
+            return Err(RegistrationError::service(kDNSServiceErr_BadReference));
+        }
+        Ok(raw)
+    }
+    fn context(&self) -> &RegisterContext {
+        unsafe { &*self.context }
+    }
+    /// Socket of the host records' own connection, if registering any
+    fn host_records_socket(&self) -> Option<Socket> {
+        lock(&self.context().host_records)
+            .as_ref()
+            .map(|records| records.socket() as _)
+    }
+    /// Processes replies to the host records without blocking, as they keep coming after
+    /// they're registered
+    fn process_host_records(&self) -> Result<()> {
+        let mut host_records = lock(&self.context().host_records);
+        if let Some(records) = host_records.as_mut() {
+            while socket_is_ready(records.socket() as _, Duration::ZERO)? {
+                records.process()?;
+            }
+        }
+        Ok(())
     }
 }
 unsafe impl Send for ServiceRef {}
+// shared with the processing thread, dns_sd refs aren't thread safe so every call into dns_sd with
+// one is made with `raw` locked, or the runtime's connection if on one
+unsafe impl Sync for ServiceRef {}
+impl ServiceRef {
+    /// Deallocates the DNSServiceRef, unregistering the service
+    fn deallocate(&self) {
+        let mut raw = lock(&self.raw);
+        if !raw.is_null() {
+            trace!("Deallocating DNSServiceRef");
+            unsafe { DNSServiceRefDeallocate(*raw) };
+            *raw = null_mut();
+        }
+    }
+}
+impl Operation for ServiceRef {
+    fn after_process(&self, connection: &Connection) {
+        if let Err(e) = rename(self, Some(connection)) {
+            error!("Service is no longer registered: {:?}", e);
+            let context = self.context();
+            *lock(&context.status) = ConnectionStatus::Stopped;
+            context.events.send(RegistrationEvent::failed(&e));
+        }
+    }
+    fn lost(&self) {
+        // the ref went with the connection, as did its records until they're added again
+        *lock(&self.raw) = null_mut();
+        let context = self.context();
+        if context.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        *lock(&context.status) = ConnectionStatus::Reconnecting { attempt: 1 };
+        context.events.send(RegistrationEvent::DaemonLost);
+    }
+    fn restart(&self, connection: &Connection) {
+        if self.context().stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        match reregister(self, Some(connection)) {
+            Ok(()) => info!("Registered again on new connection"),
+            Err(e) => error!(
+                "Error registering again, service stays unregistered: {:?}",
+                e
+            ),
+        }
+    }
+    fn sockets(&self) -> Vec<Socket> {
+        self.host_records_socket().into_iter().collect()
+    }
+    fn process_sockets(&self) {
+        if let Err(e) = self.process_host_records() {
+            // they're registered again along with the service once the daemon's back
+            error!("Lost host records connection: {:?}", e);
+            *lock(&self.context().host_records) = None;
+        }
+    }
+}
 impl Drop for ServiceRef {
     fn drop(&mut self) {
-        unsafe {
-            trace!("Dropping service");
-            if !self.raw.is_null() {
-                trace!("Deallocating DNSServiceRef");
-                DNSServiceRefDeallocate(self.raw);
-                self.raw = null_mut();
-                _ = Box::from_raw(self.context);
+        trace!("Dropping service");
+        let connection = self.connection();
+        self.deallocate();
+        drop(connection);
+        // context outlives the ref, record handles may still use it
+        _ = unsafe { Box::from_raw(self.context) };
+    }
+}
+
+impl RegisteredDnsService {
+    /// Socket of the service's own connection, or the runtime's if registered on one, as a
+    /// subordinate ref has none
+    fn socket(&self) -> i32 {
+        match &self.service.runtime {
+            Some(runtime) => runtime.socket() as _,
+            None => unsafe { DNSServiceRefSockFD(self.service.raw()) },
+        }
+    }
+    /// Processes replies that are ready without blocking, returning the lifecycle events they
+    /// produced
+    ///
+    /// For event loops watching `as_raw_fd`, replies are only processed here with
+    /// `with_manual_processing`. There's no socket to watch while reconnecting after losing the
+    /// daemon, so keep calling it to register again. Otherwise the registration's thread or
+    /// runtime processes replies & this only returns their events. Returns none once `events`
+    /// has taken the receiver.
+    pub fn process_ready(&mut self) -> Result<Vec<RegistrationEvent>> {
+        if self.manual_processing {
+            self.process_manual()?;
+        }
+        Ok(self
+            .events
+            .as_ref()
+            .map(|events| events.try_iter().collect())
+            .unwrap_or_default())
+    }
+    /// Processes replies to the service's own ref that are ready, with manual processing,
+    /// registering again once it's time to after losing the daemon
+    fn process_manual(&mut self) -> Result<()> {
+        let context = self.service.context();
+        if let Some((backoff, next_attempt)) = self.reconnect.as_mut() {
+            if Instant::now() < *next_attempt {
+                return Ok(());
             }
+            match reregister(&self.service, None) {
+                Ok(()) => {
+                    info!("Registered again after {} attempts", backoff.attempt());
+                    self.reconnect = None;
+                }
+                Err(e) => {
+                    warn!(
+                        "Registering again failed on attempt {}: {:?}",
+                        backoff.attempt(),
+                        e
+                    );
+                    *next_attempt = Instant::now() + backoff.next_delay();
+                    *lock(&context.status) = ConnectionStatus::Reconnecting {
+                        attempt: backoff.attempt(),
+                    };
+                }
+            }
+            return Ok(());
+        }
+        // unregistered after failing
+        if self.service.raw().is_null() {
+            return Ok(());
+        }
+        let result = self.service.process_host_records().and_then(|()| {
+            while socket_is_ready(self.socket() as _, Duration::ZERO)? {
+                process_reply(&self.service)?;
+            }
+            Ok(())
+        });
+        let e = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        // deallocated so the closed socket isn't watched
+        self.service.deallocate();
+        if !daemon_lost(&e) {
+            error!("Error processing, service is no longer registered: {:?}", e);
+            *lock(&context.status) = ConnectionStatus::Stopped;
+            context.events.send(RegistrationEvent::failed(&e));
+            return Err(e);
         }
+        error!("Lost daemon connection: {:?}, registering again", e);
+        context.events.send(RegistrationEvent::DaemonLost);
+        let mut backoff = Backoff::new();
+        let next_attempt = Instant::now() + backoff.next_delay();
+        *lock(&context.status) = ConnectionStatus::Reconnecting {
+            attempt: backoff.attempt(),
+        };
+        self.reconnect = Some((backoff, next_attempt));
+        Ok(())
+    }
+    /// Stops the processing thread & deallocates the service, reporting any processing error
+    fn stop(&mut self) -> Result<()> {
+        let context = self.service.context();
+        if context.stopped.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        trace!("Stopping registration...");
+        let result = match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                error!("Registration thread panicked");
+                Err(RegistrationError::service(kDNSServiceErr_Unknown))
+            }),
+            None => Ok(()),
+        };
+        let connection = self.service.connection();
+        self.service.deallocate();
+        drop(connection);
+        *lock(&context.host_records) = None;
+        *lock(&context.status) = ConnectionStatus::Stopped;
+        trace!("Service unregistered");
+        result
+    }
+    /// Connection status to the daemon, reconnecting after it's lost (i.e. daemon restarted)
+    pub fn status(&self) -> ConnectionStatus {
+        match self.service.runtime.as_ref().map(Runtime::status) {
+            Some(status @ ConnectionStatus::Reconnecting { .. }) => status,
+            _ => *lock(&self.service.context().status),
+        }
+    }
+    /// Takes the receiver of lifecycle events, i.e. renames or losing the daemon, only available
+    /// once
+    ///
+    /// Events are dropped if not received in time.
+    pub fn events(&mut self) -> Option<Receiver<RegistrationEvent>> {
+        self.events.take()
+    }
+    /// Unregisters the service, waiting until it's removed
+    ///
+    /// Fails if the daemon connection was lost & not established again yet.
+    pub fn unregister(mut self) -> Result<()> {
+        self.stop()
+    }
+    fn registered<T>(&self, field: impl FnOnce(&DNSServiceRegisterReply) -> T) -> Option<T> {
+        lock(&self.service.context().reply).as_ref().map(field)
+    }
+    /// Name the service is registered with, which differs from the one requested if renamed
+    /// after a conflict
+    pub fn name(&self) -> String {
+        self.registered(|reply| reply.name.clone())
+            .unwrap_or_default()
+    }
+    /// Service type registered, i.e. `_http._tcp.`
+    pub fn regtype(&self) -> String {
+        self.registered(|reply| reply.regtype.clone())
+            .unwrap_or_default()
+    }
+    /// Domain the service is registered in, i.e. `local.`
+    pub fn domain(&self) -> String {
+        self.registered(|reply| reply.domain.clone())
+            .unwrap_or_default()
+    }
+    /// Adds a record to the service, with a TTL in seconds or 0 for the default
+    ///
+    /// Avahi's dns_sd compat layer on Linux can't add records, failing with
+    /// `ServiceErrorKind::Unsupported`.
+    pub fn add_record(&self, data: &RecordData, ttl: u32) -> Result<RecordHandle> {
+        let rrtype = data.rrtype();
+        let data = data.to_bytes()?;
+        let _connection = self.service.connection();
+        let raw = self.service.registered_raw()?;
+        let record = unsafe { add_record(*raw, rrtype, &data, ttl)? };
+        let context = self.service.context();
+        let id = context.next_record_id.fetch_add(1, Ordering::SeqCst);
+        lock(&context.records).push(AddedRecord {
+            id,
+            raw: record,
+            rrtype,
+            data,
+            ttl,
+        });
+        Ok(RecordHandle {
+            service: self.service.clone(),
+            id,
+            rrtype,
+        })
+    }
+    /// Replaces the TXT record being advertised, browsers see the change without the service
+    /// being removed
+    pub fn update_txt(&mut self, txt: &TXTRecord) -> Result<()> {
+        let txt = encode_txt(txt, self.txt_size_limit)?;
+        let mut data = txt.clone();
+        if data.is_empty() {
+            // unlike registration, updates need the single empty string an empty record holds
+            data.push(0);
+        }
+        // hold the ref so it isn't replaced by a rename while updating
+        let _connection = self.service.connection();
+        let raw = self.service.registered_raw()?;
+        let r = unsafe {
+            DNSServiceUpdateRecord(
+                *raw,
+                null_mut(),
+                0,
+                data.len() as u16,
+                data.as_ptr() as *const c_void,
+                0,
+            )
+        };
+        if r != kDNSServiceErr_NoError {
+            error!("Error updating TXT record: {}", r);
+            return Err(RegistrationError::service(r));
+        }
+        *lock(&self.service.context().txt) = Some(txt);
+        Ok(())
     }
 }
 
-impl RegisteredDnsService {}
+#[cfg(not(target_os = "windows"))]
+impl std::os::unix::io::AsRawFd for RegisteredDnsService {
+    /// Socket to watch for replies to process with `process_ready`, which changes after
+    /// registering again once the daemon connection's lost
+    ///
+    /// On a runtime it's the runtime's connection, owned & shared by everything on it.
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.socket()
+    }
+}
+#[cfg(target_os = "windows")]
+impl std::os::windows::io::AsRawSocket for RegisteredDnsService {
+    /// Socket to watch for replies to process with `process_ready`, which changes after
+    /// registering again once the daemon connection's lost
+    ///
+    /// On a runtime it's the runtime's connection, owned & shared by everything on it.
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket {
+        self.socket() as _
+    }
+}
 
-// In order to signal the blocked thread, we close its socket to unblock it
 impl Drop for RegisteredDnsService {
     fn drop(&mut self) {
-        unsafe {
-            trace!("Closing socket to signal service cleanup...");
-            libc::close(self.socket);
+        if let Err(e) = self.stop() {
+            error!("Error unregistering service: {:?}", e);
         }
     }
 }
-fn run_thread(service: ServiceRef) {
-    std::thread::spawn(move || loop {
-        unsafe {
-            trace!("Processing...");
-            let r = DNSServiceProcessResult(service.raw);
-            if r != kDNSServiceErr_NoError {
-                error!("Error processing: {}, exiting thread", r);
-                break;
+/// Processes replies for the service until stopped, registering it again if renamed or the
+/// daemon connection is lost
+fn run_thread(service: Arc<ServiceRef>) -> JoinHandle<Result<()>> {
+    std::thread::Builder::new()
+        .name("astro-dnssd: registration".into())
+        .spawn(move || {
+            let context = service.context();
+            loop {
+                let e = match process(&service) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                };
+                if context.stopped.load(Ordering::SeqCst) {
+                    return Err(e);
+                }
+                if !daemon_lost(&e) {
+                    error!("Error processing, service is no longer registered: {:?}", e);
+                    *lock(&context.status) = ConnectionStatus::Stopped;
+                    context.events.send(RegistrationEvent::failed(&e));
+                    return Err(e);
+                }
+                error!("Lost daemon connection: {:?}, registering again", e);
+                context.events.send(RegistrationEvent::DaemonLost);
+                if !reconnect(&service) {
+                    return Err(e);
+                }
+            }
+        })
+        .expect("Failed to start registration thread")
+}
+/// True if the error means the daemon connection's gone, rather than the registration failing
+fn daemon_lost(e: &RegistrationError) -> bool {
+    matches!(e, RegistrationError::IoError(_))
+        || e.service_error_kind() == Some(ServiceErrorKind::ServiceNotRunning)
+}
+/// Processes replies until stopped or there's an error
+fn process(service: &ServiceRef) -> Result<()> {
+    let context = service.context();
+    while !context.stopped.load(Ordering::SeqCst) {
+        let socket = unsafe { DNSServiceRefSockFD(service.raw()) };
+        let sockets: Vec<Socket> = std::iter::once(socket as _)
+            .chain(service.host_records_socket())
+            .collect();
+        let ready = sockets_ready(&sockets, Some(PROCESS_POLL_INTERVAL))?;
+        if ready[1..].contains(&true) {
+            service.process_host_records()?;
+        }
+        if ready[0] {
+            process_reply(service)?;
+        }
+    }
+    Ok(())
+}
+/// Processes a reply to the service's own ref, blocking until there is one
+fn process_reply(service: &ServiceRef) -> Result<()> {
+    trace!("Processing...");
+    // locked so record & TXT updates from other threads don't call in at the same time
+    let r = unsafe { DNSServiceProcessResult(*service.registered_raw()?) };
+    if r != kDNSServiceErr_NoError {
+        error!("Error processing: {}", r);
+        return Err(RegistrationError::service(r));
+    }
+    rename(service, None)
+}
+/// Registers again under the name picked after a conflict, if any
+fn rename(service: &ServiceRef, connection: Option<&Connection>) -> Result<()> {
+    let context = service.context();
+    let new_name = lock(&context.pending_rename).take();
+    if let Some(new_name) = new_name {
+        if context.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let new_raw = unsafe { context.register(service.context, Some(&new_name), connection) }
+            .map_err(|e| {
+                error!("Error registering as {}: {:?}", new_name, e);
+                e
+            })?;
+        replace_raw(service, new_raw);
+    }
+    Ok(())
+}
+/// Registers again with backoff until it succeeds, false if stopped first
+fn reconnect(service: &ServiceRef) -> bool {
+    let context = service.context();
+    let mut backoff = Backoff::new();
+    loop {
+        let delay = backoff.next_delay();
+        let attempt = backoff.attempt();
+        *lock(&context.status) = ConnectionStatus::Reconnecting { attempt };
+        let until = Instant::now() + delay;
+        while Instant::now() < until {
+            if context.stopped.load(Ordering::SeqCst) {
+                return false;
+            }
+            std::thread::sleep(PROCESS_POLL_INTERVAL.min(delay));
+        }
+        match reregister(service, None) {
+            Ok(()) => {
+                info!("Registered again after {} attempts", attempt);
+                return true;
+            }
+            Err(e) => {
+                warn!("Registering again failed on attempt {}: {:?}", attempt, e);
+            }
+        }
+    }
+}
+/// Registers the service & any host records again, with the name it last had
+fn reregister(service: &ServiceRef, connection: Option<&Connection>) -> Result<()> {
+    let context = service.context();
+    if let Some(host) = &context.host {
+        if !context.host_addresses.is_empty() {
+            // drop the old records first so the new ones don't conflict with them
+            *lock(&context.host_records) = None;
+            let records = HostRecords::register(
+                host,
+                &context.host_addresses,
+                context.interface_index,
+                context.timeout,
+            )?;
+            *lock(&context.host_records) = Some(records);
+        }
+    }
+    let name = lock(&context.reply)
+        .as_ref()
+        .map(|reply| reply.name.clone())
+        .or_else(|| lock(&context.base_name).clone());
+    context.reregistering.store(true, Ordering::SeqCst);
+    let new_raw = unsafe { context.register(service.context, name.as_deref(), connection) }?;
+    replace_raw(service, new_raw);
+    Ok(())
+}
+/// Replaces the service's DNSServiceRef with a new registration, adding records to it again
+fn replace_raw(service: &ServiceRef, new_raw: DNSServiceRef) {
+    let context = service.context();
+    let mut raw = lock(&service.raw);
+    if !raw.is_null() {
+        unsafe { DNSServiceRefDeallocate(*raw) };
+    }
+    *raw = new_raw;
+    // records went with the old registration, so add them to the new one
+    lock(&context.records).retain_mut(|record| {
+        match unsafe { add_record(new_raw, record.rrtype, &record.data, record.ttl) } {
+            Ok(new_record) => {
+                record.raw = new_record;
+                true
+            }
+            Err(e) => {
+                error!("Dropping record lost with old registration: {:?}", e);
+                false
             }
         }
     });
 }
-pub fn register_service(service: DNSServiceBuilder) -> Result<RegisteredDnsService> {
-    unsafe {
-        let c_name: Option<CString>;
-        if let Some(n) = &service.name {
-            c_name = Some(CString::new(n.as_str()).map_err(|_| RegistrationError::InvalidString)?);
-        } else {
-            c_name = None;
-        }
-        let c_name = c_name.as_ref();
-        let service_type =
-            CString::new(service.regtype.as_str()).map_err(|_| RegistrationError::InvalidString)?;
-        let txt = service.txt.map(TXTRecord::from);
-        let (txt_record, txt_len) = match &txt {
-            Some(txt) => (txt.raw_bytes_ptr(), txt.raw_bytes_len()),
-            None => (ptr::null(), 0),
+/// Builder's arguments, validated & converted for the daemon
+struct Arguments {
+    regtype: CString,
+    interface_index: u32,
+    txt: Option<Vec<u8>>,
+    domain: Option<CString>,
+    host: Option<CString>,
+}
+impl Arguments {
+    fn new(service: &DNSServiceBuilder) -> Result<Self> {
+        let regtype = regtype_with_subtypes(&service.regtype, &service.subtypes)
+            .map_err(RegistrationError::InvalidSubtype)?;
+        let regtype = CString::new(regtype)
+            .map_err(|_| RegistrationError::InvalidString { field: "regtype" })?;
+        let interface_index = interface_index(service.interface.as_ref())
+            .map_err(RegistrationError::UnknownInterface)?;
+        let txt = service.txt_bytes()?;
+        let domain = service
+            .domain
+            .as_deref()
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| RegistrationError::InvalidString { field: "domain" })?;
+        let host = service
+            .fqdn_host()
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| RegistrationError::InvalidString { field: "host" })?;
+        if host.is_none() && !service.host_addresses.is_empty() {
+            return Err(RegistrationError::MissingHost);
+        }
+        Ok(Arguments {
+            regtype,
+            interface_index,
+            txt,
+            domain,
+            host,
+        })
+    }
+    /// Host to register records for, if there are addresses to register
+    fn record_host<'a>(&'a self, service: &DNSServiceBuilder) -> Option<&'a CStr> {
+        self.host
+            .as_deref()
+            .filter(|_| !service.host_addresses.is_empty())
+    }
+}
+
+/// Channel the first reply to a registration is sent on
+type ReplyReceiver = (Receiver<Result<DNSServiceRegisterReply>>, Arc<ChannelWaker>);
+
+/// Registers the service, processing replies on a thread or the runtime
+fn start(
+    service: DNSServiceBuilder,
+    arguments: Arguments,
+    host_records: Option<HostRecords>,
+) -> Result<(RegisteredDnsService, ReplyReceiver)> {
+    // renaming is done here with a strategy, so the daemon mustn't do it first
+    let flags = if service.no_auto_rename || service.rename.is_some() {
+        kDNSServiceFlagsNoAutoRename
+    } else {
+        0
+    };
+
+    let (tx, rx, waker) = waking_channel::<Result<DNSServiceRegisterReply>>();
+    let (events, events_rx) = event_channel();
+    let context = Box::into_raw(Box::new(RegisterContext {
+        tx,
+        timeout: service.timeout,
+        reply: Mutex::new(None),
+        regtype: arguments.regtype,
+        domain: arguments.domain,
+        host: arguments.host,
+        interface_index: arguments.interface_index,
+        port: service.port,
+        flags,
+        txt: Mutex::new(arguments.txt),
+        rename: service.rename,
+        base_name: Mutex::new(service.name.clone()),
+        pending_rename: Mutex::new(None),
+        rename_attempts: AtomicU32::new(1),
+        records: Mutex::new(Vec::new()),
+        next_record_id: AtomicU64::new(0),
+        stopped: AtomicBool::new(false),
+        events,
+        status: Mutex::new(ConnectionStatus::Connected),
+        reregistering: AtomicBool::new(false),
+        host_addresses: service.host_addresses.clone(),
+        host_records: Mutex::new(host_records),
+    }));
+
+    let runtime = service.runtime;
+    let connection = runtime.as_ref().map(Runtime::connection);
+    let raw = match unsafe {
+        (*context).register(context, service.name.as_deref(), connection.as_deref())
+    } {
+        Ok(raw) => raw,
+        Err(e) => {
+            _ = unsafe { Box::from_raw(context) };
+            return Err(e);
+        }
+    };
+    // process callback
+    let raw_service = Arc::new(ServiceRef::new(raw, context, runtime.clone()));
+    let manual_processing = service.manual_processing && runtime.is_none();
+    let thread = match &runtime {
+        Some(runtime) => {
+            let operation: Weak<ServiceRef> = Arc::downgrade(&raw_service);
+            runtime.add(operation);
+            None
+        }
+        // the caller processes replies instead
+        None if manual_processing => None,
+        // spin a thread that keeps the registration working
+        None => Some(run_thread(raw_service.clone())),
+    };
+    drop(connection);
+    let registered = RegisteredDnsService {
+        service: raw_service,
+        txt_size_limit: service.txt_size_limit,
+        thread,
+        events: Some(events_rx),
+        manual_processing,
+        reconnect: None,
+    };
+    Ok((registered, (rx, waker)))
+}
+
+/// Checks the first reply to a registration, unregistering the service if it failed
+fn finish(
+    registered: RegisteredDnsService,
+    reply: Result<Result<DNSServiceRegisterReply>, RecvTimeoutError>,
+) -> Result<RegisteredDnsService> {
+    match reply {
+        Ok(Ok(reply)) => {
+            info!(
+                "Registered {} as {} in {}",
+                reply.name, reply.regtype, reply.domain
+            );
+            Ok(registered)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(RecvTimeoutError::Timeout) => {
+            error!("Timed out waiting for callback");
+            Err(RegistrationError::Timeout)
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            error!("Registration callback channel closed");
+            Err(IoError::from(ErrorKind::BrokenPipe).into())
+        }
+    }
+}
+
+/// Receives the first reply, processing replies to the service's own ref while waiting as
+/// nothing else does with manual processing
+fn recv_processing(
+    rx: &Receiver<Result<DNSServiceRegisterReply>>,
+    service: &ServiceRef,
+    timeout: Duration,
+) -> Result<Result<DNSServiceRegisterReply>, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match rx.try_recv() {
+            Ok(reply) => return Ok(reply),
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {}
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        let socket = unsafe { DNSServiceRefSockFD(service.raw()) };
+        let result = socket_is_ready(socket as _, remaining)
+            .map_err(RegistrationError::from)
+            .and_then(|ready| match ready {
+                true => process_reply(service),
+                false => Ok(()),
+            });
+        if let Err(e) = result {
+            return Ok(Err(e));
+        }
+    }
+}
+
+/// Receives the first reply like `recv_processing`, waiting without blocking
+#[cfg(feature = "async-io")]
+async fn recv_processing_async(
+    rx: Receiver<Result<DNSServiceRegisterReply>>,
+    service: &ServiceRef,
+    timeout: Duration,
+) -> Result<Result<DNSServiceRegisterReply>, RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match rx.try_recv() {
+            Ok(reply) => return Ok(reply),
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {}
+        }
+        if Instant::now() >= deadline {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        let socket = unsafe { DNSServiceRefSockFD(service.raw()) };
+        let result = match socket_readable_async(socket as _, deadline).await {
+            Ok(true) => process_reply(service),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e.into()),
         };
+        if let Err(e) = result {
+            return Ok(Err(e));
+        }
+    }
+}
 
-        let (tx, rx) = sync_channel::<Result<DNSServiceRegisterReply>>(4);
-        let tx = Box::into_raw(Box::new(tx));
+pub fn register_service(service: DNSServiceBuilder) -> Result<RegisteredDnsService> {
+    let arguments = Arguments::new(&service)?;
+    let host_records = arguments
+        .record_host(&service)
+        .map(|host| {
+            HostRecords::register(
+                host,
+                &service.host_addresses,
+                arguments.interface_index,
+                service.timeout,
+            )
+        })
+        .transpose()?;
+    let timeout = service.timeout;
+    let (registered, (rx, _waker)) = start(service, arguments, host_records)?;
+    let reply = match registered.manual_processing {
+        true => recv_processing(&rx, &registered.service, timeout),
+        false => rx.recv_timeout(timeout),
+    };
+    finish(registered, reply)
+}
 
-        let mut raw: DNSServiceRef = null_mut();
-        let result = DNSServiceRegister(
-            &mut raw,
-            0,
-            0,
-            c_name.map_or(null_mut(), |c| c.as_ptr()),
-            service_type.as_ptr(),
-            ptr::null(),
-            ptr::null(),
-            service.port.to_be(),
-            txt_len,
-            txt_record,
-            Some(register_reply),
-            tx as _,
-        );
-        if result == kDNSServiceErr_NoError {
-            // process callback
-            let socket = DNSServiceRefSockFD(raw);
-            let service = RegisteredDnsService { socket };
-            let raw_service = ServiceRef::new(raw, tx as _);
+#[cfg(feature = "async-io")]
+pub async fn register_service_async(service: DNSServiceBuilder) -> Result<RegisteredDnsService> {
+    let arguments = Arguments::new(&service)?;
+    let host_records = match arguments.record_host(&service) {
+        Some(host) => Some(
+            HostRecords::register_async(
+                host,
+                &service.host_addresses,
+                arguments.interface_index,
+                service.timeout,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    let timeout = service.timeout;
+    let (registered, (rx, waker)) = start(service, arguments, host_records)?;
+    let reply = match registered.manual_processing {
+        true => recv_processing_async(rx, &registered.service, timeout).await,
+        false => recv_timeout_async(rx, &waker, timeout).await,
+    };
+    finish(registered, reply)
+}
 
-            // spin a thread that keeps the registration working
-            run_thread(raw_service);
+#[cfg(all(test, feature = "async-io"))]
+mod tests {
+    use crate::error::ServiceErrorKind;
+    use crate::DNSServiceBuilder;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
 
-            match rx.recv_timeout(CALLBACK_TIMEOUT) {
-                Ok(Ok(_reply)) => Ok(service),
-                Ok(Err(e)) => Err(e),
-                Err(e) => {
-                    error!("Error waiting for callback: {:?}", e);
-                    Err(RegistrationError::ServiceError(0))
-                }
-            }
-        } else {
-            Err(RegistrationError::ServiceError(result))
+    #[test]
+    fn dropping_register_async_unregisters() {
+        let service = || {
+            DNSServiceBuilder::new("_astro-test._tcp", 4321)
+                .with_name("astro-dnssd dropped registration")
+                .with_no_auto_rename()
+        };
+        let mut registering = Box::pin(service().register_async());
+        let mut cx = Context::from_waker(Waker::noop());
+        if let Poll::Ready(_result) = registering.as_mut().poll(&mut cx) {
+            // failed right away without a daemon, so there's nothing pending to drop
+            return;
+        }
+        drop(registering);
+        // the name's free again, the dropped registration doesn't linger
+        match async_io::block_on(service().register_async()) {
+            Ok(registered) => drop(registered),
+            Err(e) => assert_ne!(
+                e.service_error_kind(),
+                Some(ServiceErrorKind::NameConflict),
+                "{}",
+                e
+            ),
         }
     }
 }
@@ -0,0 +1,333 @@
+//! Event loop running browsers & registrations on one shared daemon connection
+
+use crate::error::Error;
+use crate::ffi::apple::{
+    kDNSServiceErr_NoError, kDNSServiceErr_ServiceNotRunning, kDNSServiceFlagsShareConnection,
+    DNSServiceCreateConnection, DNSServiceErrorType, DNSServiceFlags, DNSServiceProcessResult,
+    DNSServiceRef, DNSServiceRefDeallocate, DNSServiceRefSockFD,
+};
+use crate::non_blocking::{sockets_ready, Socket, WakeSocket};
+use crate::status::{Backoff, ConnectionStatus};
+use std::fmt;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// Locks a mutex, carrying on with the data if another thread panicked holding it
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Daemon connection shared by everything on a runtime, null while reconnecting
+///
+/// dns_sd isn't thread safe on a shared connection, so it's locked while calling into it.
+pub(crate) struct Connection {
+    raw: DNSServiceRef,
+}
+impl Connection {
+    /// Ref & flags to start an operation sharing the connection with, fails if it's lost
+    pub(crate) fn share(&self) -> Result<(DNSServiceRef, DNSServiceFlags), DNSServiceErrorType> {
+        if self.raw.is_null() {
+            return Err(kDNSServiceErr_ServiceNotRunning);
+        }
+        Ok((self.raw, kDNSServiceFlagsShareConnection))
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.raw.is_null() {
+            trace!("Deallocating shared connection");
+            unsafe { DNSServiceRefDeallocate(self.raw) };
+        }
+    }
+}
+unsafe impl Send for Connection {}
+
+/// Ref & flags to start an operation with, sharing the runtime's connection if given
+pub(crate) fn initial_ref(
+    connection: Option<&Connection>,
+) -> Result<(DNSServiceRef, DNSServiceFlags), DNSServiceErrorType> {
+    match connection {
+        Some(connection) => connection.share(),
+        None => Ok((null_mut(), 0)),
+    }
+}
+
+/// Browse or registration on a runtime, called on its thread with the connection locked
+pub(crate) trait Operation: Send + Sync {
+    /// Carries on with anything replies left to do, as callbacks can't call into dns_sd
+    fn after_process(&self, connection: &Connection);
+    /// Connection was lost, taking the operation's refs with it without deallocating them
+    fn lost(&self);
+    /// Starts the operation again on a new connection
+    fn restart(&self, connection: &Connection);
+    /// Sockets of refs the operation has off the shared connection, waited on along with it
+    fn sockets(&self) -> Vec<Socket> {
+        Vec::new()
+    }
+    /// Processes replies ready on the operation's own sockets
+    fn process_sockets(&self) {}
+}
+
+/// State shared with the runtime's thread
+struct Shared {
+    connection: Mutex<Connection>,
+    operations: Mutex<Vec<Weak<dyn Operation>>>,
+    status: Mutex<ConnectionStatus>,
+    /// Backoff & time of the next attempt while connecting again
+    reconnect: Mutex<Option<(Backoff, Instant)>>,
+    stopped: AtomicBool,
+    /// Wakes the thread from polling
+    wake: WakeSocket,
+}
+impl Shared {
+    /// Operations still running, dropping any that are gone
+    ///
+    /// Dropped after unlocking the connection, as dropping the last ref to one locks it.
+    fn operations(&self) -> Vec<Arc<dyn Operation>> {
+        let mut operations = lock(&self.operations);
+        let running: Vec<Arc<dyn Operation>> =
+            operations.iter().filter_map(Weak::upgrade).collect();
+        operations.retain(|operation| operation.strong_count() > 0);
+        running
+    }
+    /// Processes a reply, deallocating the connection if it's lost
+    fn process(&self) -> Result<(), DNSServiceErrorType> {
+        let mut connection = lock(&self.connection);
+        let operations = self.operations();
+        let r = unsafe { DNSServiceProcessResult(connection.raw) };
+        if r != kDNSServiceErr_NoError {
+            operations.iter().for_each(|operation| operation.lost());
+            unsafe { DNSServiceRefDeallocate(connection.raw) };
+            connection.raw = null_mut();
+        } else {
+            operations
+                .iter()
+                .for_each(|operation| operation.after_process(&connection));
+        }
+        drop(connection);
+        if r != kDNSServiceErr_NoError {
+            return Err(r);
+        }
+        Ok(())
+    }
+    /// Connects again, restarting every operation on the new connection
+    fn reconnect(&self) -> Result<(), DNSServiceErrorType> {
+        let raw = connect()?;
+        let mut connection = lock(&self.connection);
+        connection.raw = raw;
+        let operations = self.operations();
+        operations
+            .iter()
+            .for_each(|operation| operation.restart(&connection));
+        drop(connection);
+        *lock(&self.status) = ConnectionStatus::Connected;
+        Ok(())
+    }
+    fn socket(&self) -> Socket {
+        unsafe { DNSServiceRefSockFD(lock(&self.connection).raw) as _ }
+    }
+    /// Sockets operations have off the connection, with the operation to process each
+    ///
+    /// Only weak refs are kept while waiting, so operations dropped meanwhile stop right away.
+    fn operation_sockets(&self) -> Vec<(Weak<dyn Operation>, Socket)> {
+        self.operations()
+            .iter()
+            .flat_map(|operation| {
+                let weak = Arc::downgrade(operation);
+                operation
+                    .sockets()
+                    .into_iter()
+                    .map(move |socket| (weak.clone(), socket))
+            })
+            .collect()
+    }
+    /// Processes operations with replies ready on their own sockets
+    fn process_operation_sockets(&self, sockets: &[(Weak<dyn Operation>, Socket)], ready: &[bool]) {
+        for ((operation, _socket), ready) in sockets.iter().zip(ready) {
+            if let (true, Some(operation)) = (*ready, operation.upgrade()) {
+                operation.process_sockets();
+            }
+        }
+    }
+    /// Time of the next attempt to connect again, if the connection was lost
+    fn next_attempt(&self) -> Option<Instant> {
+        lock(&self.reconnect)
+            .as_ref()
+            .map(|(_backoff, next_attempt)| *next_attempt)
+    }
+    /// Processes a reply, scheduling connecting again with backoff if the connection is lost
+    fn process_reply(&self) {
+        if let Err(e) = self.process() {
+            error!("Lost daemon connection: {}, connecting again", e);
+            let mut backoff = Backoff::new();
+            let next_attempt = Instant::now() + backoff.next_delay();
+            *lock(&self.status) = ConnectionStatus::Reconnecting {
+                attempt: backoff.attempt(),
+            };
+            *lock(&self.reconnect) = Some((backoff, next_attempt));
+        }
+    }
+    /// Connects again if it's time for the next attempt
+    fn try_reconnect(&self) {
+        let mut reconnect = lock(&self.reconnect);
+        let (backoff, next_attempt) = match reconnect.as_mut() {
+            Some(reconnect) => reconnect,
+            None => return,
+        };
+        if Instant::now() < *next_attempt {
+            return;
+        }
+        match self.reconnect() {
+            Ok(()) => {
+                info!("Connected again after {} attempts", backoff.attempt());
+                *reconnect = None;
+            }
+            Err(e) => {
+                warn!(
+                    "Connecting again failed on attempt {}: {}",
+                    backoff.attempt(),
+                    e
+                );
+                *next_attempt = Instant::now() + backoff.next_delay();
+                *lock(&self.status) = ConnectionStatus::Reconnecting {
+                    attempt: backoff.attempt(),
+                };
+            }
+        }
+    }
+}
+
+fn connect() -> Result<DNSServiceRef, DNSServiceErrorType> {
+    let mut raw: DNSServiceRef = null_mut();
+    let r = unsafe { DNSServiceCreateConnection(&mut raw) };
+    if r != kDNSServiceErr_NoError {
+        return Err(r);
+    }
+    Ok(raw)
+}
+
+/// Processes replies for every operation until stopped, connecting again with backoff if the
+/// daemon connection is lost
+fn run(shared: &Shared) {
+    let wake = shared.wake.socket();
+    while !shared.stopped.load(Ordering::SeqCst) {
+        let next_attempt = shared.next_attempt();
+        let operation_sockets = shared.operation_sockets();
+        // the connection's only waited on while it's connected
+        let mut sockets = vec![wake];
+        if next_attempt.is_none() {
+            sockets.push(shared.socket());
+        }
+        sockets.extend(operation_sockets.iter().map(|(_operation, socket)| *socket));
+        let timeout =
+            next_attempt.map(|next_attempt| next_attempt.saturating_duration_since(Instant::now()));
+        let ready = sockets_ready(&sockets, timeout);
+        let ready = match ready {
+            Ok(ready) => ready,
+            Err(e) => {
+                error!("Error polling daemon connection, stopping runtime: {}", e);
+                break;
+            }
+        };
+        if ready[0] {
+            shared.wake.drain();
+            continue;
+        }
+        let own_ready = &ready[ready.len() - operation_sockets.len()..];
+        shared.process_operation_sockets(&operation_sockets, own_ready);
+        match next_attempt {
+            None if ready[1] => shared.process_reply(),
+            Some(_next_attempt) => shared.try_reconnect(),
+            None => {}
+        }
+    }
+    trace!("Runtime thread stopped");
+}
+
+struct RuntimeInner {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+impl Drop for RuntimeInner {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // so the thread notices it's stopped
+        self.shared.wake.notify();
+        if let Some(thread) = self.thread.take() {
+            // the last ref may be dropped by an operation on the runtime's own thread
+            if thread.thread().id() != std::thread::current().id() && thread.join().is_err() {
+                error!("Runtime thread panicked");
+            }
+        }
+    }
+}
+
+/// Runs browsers & registrations on a single daemon connection & thread, instead of a thread each
+///
+/// Pass it to `DNSServiceBuilder::with_runtime` or `ServiceBrowserBuilder::with_runtime`. The
+/// thread stops once the runtime & everything started on it are dropped.
+#[derive(Clone)]
+pub struct Runtime {
+    inner: Arc<RuntimeInner>,
+}
+impl fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Runtime {{ status: {:?} }}", self.status())
+    }
+}
+impl Runtime {
+    /// Connects to the daemon & starts the thread processing its replies
+    ///
+    /// Avahi's dns_sd compat layer on Linux can't share a connection, failing with
+    /// `ServiceErrorKind::Unsupported`.
+    pub fn new() -> Result<Runtime, Error> {
+        let raw =
+            connect().map_err(|e| Error::service_or_unsupported(e, "Sharing a connection"))?;
+        let connection = Connection { raw };
+        let wake = WakeSocket::new()?;
+        let shared = Arc::new(Shared {
+            connection: Mutex::new(connection),
+            operations: Mutex::new(Vec::new()),
+            status: Mutex::new(ConnectionStatus::Connected),
+            reconnect: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+            wake,
+        });
+        let thread = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("astro-dnssd: runtime".into())
+                .spawn(move || run(&shared))?
+        };
+        Ok(Runtime {
+            inner: Arc::new(RuntimeInner {
+                shared,
+                thread: Some(thread),
+            }),
+        })
+    }
+    /// Socket of the daemon connection, which changes after connecting again
+    pub(crate) fn socket(&self) -> Socket {
+        self.inner.shared.socket()
+    }
+    /// Connection status to the daemon, reconnecting after it's lost (i.e. daemon restarted)
+    pub fn status(&self) -> ConnectionStatus {
+        *lock(&self.inner.shared.status)
+    }
+    /// Locks the shared connection, to call into dns_sd from outside the runtime's thread
+    pub(crate) fn connection(&self) -> MutexGuard<'_, Connection> {
+        lock(&self.inner.shared.connection)
+    }
+    /// Processes replies for the operation until it's dropped
+    pub(crate) fn add(&self, operation: Weak<dyn Operation>) {
+        lock(&self.inner.shared.operations).push(operation);
+        // so the thread waits on any sockets of its own
+        self.inner.shared.wake.notify();
+    }
+}
@@ -0,0 +1,135 @@
+//! Browser as an async stream, driven by tokio
+
+use super::browse::ServiceBrowser;
+use crate::browse::{Result, Service};
+use crate::ffi::apple::kDNSServiceErr_NoError;
+use crate::status::ConnectionStatus;
+use futures_core::Stream;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::mpsc::TryRecvError;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::time::{sleep_until, Sleep};
+
+/// Browser's daemon socket, owned by the browser's DNSServiceRef
+struct BrowseSocket(RawFd);
+impl AsRawFd for BrowseSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Browser as a `Stream` of services, processing daemon replies as its socket becomes readable
+///
+/// Created with `ServiceBrowser::into_stream`, browsing stops when it's dropped. Services are
+/// queued until polled, so it's cancel safe. Must be polled within a tokio runtime with IO &
+/// time enabled.
+pub struct ServiceStream {
+    /// Deregistered before the browser deallocates the socket's DNSServiceRef
+    socket: Option<AsyncFd<BrowseSocket>>,
+    /// Next attempt to browse again after losing the daemon
+    retry: Option<Pin<Box<Sleep>>>,
+    browser: ServiceBrowser,
+}
+
+impl ServiceBrowser {
+    /// Turns the browser into a `Stream` of services, for use with tokio
+    pub fn into_stream(self) -> ServiceStream {
+        self.resolve_on_thread();
+        ServiceStream {
+            socket: None,
+            retry: None,
+            browser: self,
+        }
+    }
+}
+
+impl ServiceStream {
+    /// Connection status to the daemon, browsing again after it's lost (i.e. daemon restarted)
+    pub fn status(&self) -> ConnectionStatus {
+        self.browser.status()
+    }
+
+    /// Waits until it's time to browse again, then tries to
+    fn poll_reconnect(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while let Some(next_attempt) = self.browser.next_attempt() {
+            let retry = self
+                .retry
+                .get_or_insert_with(|| Box::pin(sleep_until(next_attempt.into())));
+            if retry.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.retry = None;
+            self.browser.try_reconnect();
+        }
+        Poll::Ready(())
+    }
+
+    /// Processes replies while the browse socket is readable, until it'd block
+    fn poll_socket(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let fd = self.browser.socket();
+        let socket = match &mut self.socket {
+            Some(socket) if socket.get_ref().0 == fd => socket,
+            // SAFETY: the socket is deregistered before its ref is deallocated, when lost or dropped
+            socket => socket.insert(
+                unsafe { AsyncFd::register_with_interest(BrowseSocket(fd), Interest::READABLE) }
+                    .map_err(io::Error::from)?,
+            ),
+        };
+        let mut guard = match socket.poll_read_ready(cx) {
+            Poll::Ready(Ok(guard)) => guard,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+            Poll::Pending => return Poll::Pending,
+        };
+        if !self.browser.has_data(Duration::ZERO)? {
+            guard.clear_ready();
+            return Poll::Ready(Ok(()));
+        }
+        let r = self.browser.process_result();
+        drop(guard);
+        if r != kDNSServiceErr_NoError {
+            // deregister before the lost ref closes the socket
+            self.socket = None;
+            self.browser.lost(r);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for ServiceStream {
+    type Item = Result<Service>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // browse-only services are received with `recv_discovered` instead, so this fails once
+        if this.browser.browse_only() {
+            return Poll::Ready(this.browser.next_service());
+        }
+        loop {
+            // registered first, so a service sent after checking still wakes the task
+            this.browser.waker.register(cx.waker());
+            match this.browser.rx.try_recv() {
+                Ok(service) => return Poll::Ready(Some(service)),
+                Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+                Err(TryRecvError::Empty) => {}
+            }
+            // the runtime processes replies for browsers on it
+            if this.browser.shared.is_some() {
+                return Poll::Pending;
+            }
+            if this.poll_reconnect(cx).is_pending() {
+                return Poll::Pending;
+            }
+            match this.poll_socket(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -1,16 +1,36 @@
 #[cfg(all(not(feature = "win-bonjour"), target_os = "windows"))]
 mod windows;
 
+#[cfg(all(
+    feature = "async-io",
+    not(feature = "win-bonjour"),
+    target_os = "windows"
+))]
+pub use windows::register::register_service_async;
+#[cfg(all(feature = "tokio", not(feature = "win-bonjour"), target_os = "windows"))]
+pub use windows::stream::ServiceStream;
 #[cfg(all(not(feature = "win-bonjour"), target_os = "windows"))]
 pub use windows::{
-    browse::{browse, BrowseError, ServiceBrowser},
-    register::{register_service, RegisteredDnsService, RegistrationError},
+    browse::{browse, resolve, ServiceBrowser},
+    register::{register_service, RecordHandle, RegisteredDnsService},
+    runtime::Runtime,
 };
 
 #[cfg(any(feature = "win-bonjour", not(target_os = "windows")))]
 mod apple;
+#[cfg(all(
+    feature = "async-io",
+    any(feature = "win-bonjour", not(target_os = "windows"))
+))]
+pub use apple::register::register_service_async;
+#[cfg(all(
+    feature = "tokio",
+    any(feature = "win-bonjour", not(target_os = "windows"))
+))]
+pub use apple::stream::ServiceStream;
 #[cfg(any(feature = "win-bonjour", not(target_os = "windows")))]
 pub use apple::{
-    browse::{browse, BrowseError, ServiceBrowser},
-    register::{register_service, RegisteredDnsService, RegistrationError},
+    browse::{browse, resolve, ServiceBrowser},
+    register::{register_service, RecordHandle, RegisteredDnsService},
+    runtime::Runtime,
 };
@@ -1,7 +1,59 @@
+use crate::full_name::ServiceFullName;
 use std::os::windows::ffi::OsStrExt;
 
 pub mod browse;
 pub mod register;
+pub mod runtime;
+#[cfg(feature = "tokio")]
+pub mod stream;
+
 pub fn to_utf16<S: AsRef<std::ffi::OsStr>>(s: S) -> Vec<u16> {
     s.as_ref().encode_wide().chain(Some(0u16)).collect()
 }
+
+/// Full name as the Windows DNS API takes it, unescaped & without the root's trailing dot, i.e.
+/// `My Printer._ipp._tcp.local`
+pub fn full_name(instance: &str, regtype: &str, domain: &str) -> String {
+    format!(
+        "{}.{}.{}",
+        instance,
+        regtype.trim_end_matches('.'),
+        domain.trim_end_matches('.')
+    )
+}
+
+/// Splits a full name from the Windows DNS API into its instance, service type & domain with a
+/// trailing dot
+///
+/// Names that don't parse, i.e. unescaped instances with dots or legacy service types, are split
+/// around the expected service type, or else the last labels are taken as the type & domain.
+pub fn split_full_name(name: &str, regtype: &str) -> Option<(String, String, String)> {
+    if let Ok(full_name) = name.parse::<ServiceFullName>() {
+        return Some((
+            full_name.instance().to_string(),
+            full_name.service_type().regtype(),
+            full_name.domain().to_string(),
+        ));
+    }
+    let name = name.trim_end_matches('.');
+    // lowercasing ASCII keeps byte offsets, so they index the name too
+    let suffix = format!(".{}.", regtype.trim_end_matches('.')).to_ascii_lowercase();
+    if let Some(at) = name.to_ascii_lowercase().rfind(&suffix) {
+        let end = at + suffix.len();
+        return Some((
+            name[..at].to_string(),
+            name[at + 1..end - 1].to_string(),
+            format!("{}.", &name[end..]),
+        ));
+    }
+    let mut labels = name.rsplitn(4, '.');
+    let domain = labels.next()?;
+    let protocol = labels.next()?;
+    let service = labels.next()?;
+    let instance = labels.next()?;
+    Some((
+        instance.to_string(),
+        format!("{}.{}", service, protocol),
+        format!("{}.", domain),
+    ))
+}
@@ -1,63 +1,72 @@
-use crate::browse::{Result, Service, ServiceEventType};
+use crate::browse::{DiscoveredService, Result, Service, ServiceEventType};
+use crate::error::BrowseError;
 use crate::ffi::windows::{
+    _DNS_SERVICE_BROWSE_REQUEST__bindgen_ty_1 as BrowseCallbackUnion,
     DNS_FREE_TYPE_DnsFreeRecordList, DnsFree, DnsServiceBrowse, DnsServiceBrowseCancel,
-    _DNS_SERVICE_BROWSE_REQUEST__bindgen_ty_1 as BrowseCallbackUnion, DNS_QUERY_REQUEST_VERSION1,
-    DNS_TYPE_A, DNS_TYPE_AAAA, DNS_TYPE_PTR, DNS_TYPE_SRV, DNS_TYPE_TEXT, DWORD, PDNS_RECORD,
-    PVOID, _DNS_SERVICE_BROWSE_REQUEST, _DNS_SERVICE_CANCEL,
+    DnsServiceFreeInstance, DnsServiceResolve, DnsServiceResolveCancel,
+    _DNS_SERVICE_BROWSE_REQUEST, _DNS_SERVICE_CANCEL, _DNS_SERVICE_RESOLVE_REQUEST,
+    DNS_QUERY_REQUEST_VERSION1, DNS_TYPE_A, DNS_TYPE_AAAA, DNS_TYPE_PTR, DNS_TYPE_SRV,
+    DNS_TYPE_TEXT, DWORD, PDNS_RECORD, PDNS_SERVICE_INSTANCE, PVOID,
 };
-use crate::os::windows::to_utf16;
+use crate::interface::{interface_index, interface_name};
+use crate::non_blocking::{waking_channel, ChannelWaker, WakingSender};
+use crate::os::windows::{full_name, split_full_name, to_utf16};
+use crate::status::ConnectionStatus;
+use crate::subtype::subtype_label;
+use crate::txt::TXTRecord;
 use crate::ServiceBrowserBuilder;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::{Error as IoError, ErrorKind};
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::ptr::null_mut;
-use std::str::Utf8Error;
-use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use thiserror::Error;
 use widestring::{U16CStr, U16CString};
 use winapi::shared::winerror::DNS_REQUEST_PENDING;
 
-/// Error while browsing for DNS-SD services
-#[derive(Debug, Error)]
-pub enum BrowseError {
-    /// Timeout waiting for more data, there may be no data available at this time
-    #[error("Timeout waiting for data")]
-    Timeout,
-    /// IO Error
-    #[error("IO Error: {0}")]
-    IoError(#[from] IoError),
-    /// Error from DnsService APIs
-    #[error("Error from DNS Service APIs: {0}")]
-    DnsError(DWORD),
-    /// Error processing UTF8 string bytes from C API
-    #[error("Error creating string from UTF8: {0}")]
-    Utf8StringError(#[from] Utf8Error),
+/// State shared with the browse callback
+struct BrowseContext {
+    tx: WakingSender<Service>,
+    /// Interface browsing is limited to, 0 for all interfaces
+    interface_index: u32,
+    /// Service type browsed, to split names around that don't parse
+    regtype: String,
+    /// Last TXT record seen for each service, to notice changes in later announcements
+    txt_records: Mutex<HashMap<String, Option<TXTRecord>>>,
+}
+impl BrowseContext {
+    /// Marks a repeat announcement as a TXT update if its TXT record changed
+    fn track_txt(&self, service: &mut Service) {
+        let key = format!("{}.{}.{}", service.name, service.regtype, service.domain);
+        let mut txt_records = match self.txt_records.lock() {
+            Ok(txt_records) => txt_records,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match txt_records.insert(key, service.txt_record.clone()) {
+            Some(previous) if previous != service.txt_record => {
+                service.event_type = ServiceEventType::TxtUpdated;
+            }
+            _ => {}
+        }
+    }
 }
 enum DnsRecord {
     Ptr(String),
     Srv { port: u16, hostname: String },
-    Txt(HashMap<String, String>),
+    Txt(TXTRecord),
     A(Ipv4Addr),
     Aaaa(Ipv6Addr),
 }
 
-fn process_name(name: &str) -> Option<(String, String, String)> {
-    // split doesn't do reverse so collect then reverse...
-    let mut split = name.split('.').collect::<Vec<&str>>().into_iter().rev();
-    let domain = split.next()?;
-    let ip_protocol = split.next()?;
-    let protocol = split.next()?;
-    let name: String = split.collect::<Vec<&str>>().join(".");
-    Some((name, format!("{}.{}", protocol, ip_protocol), domain.into()))
-}
-
-fn services_from_record_list(start_record: PDNS_RECORD) -> Result<Service> {
+fn services_from_record_list(start_record: PDNS_RECORD, regtype: &str) -> Result<Service> {
     let mut service = Service {
         name: "".to_string(),
         regtype: "".to_string(),
         interface_index: None,
+        interface_name: None,
         domain: "".to_string(),
         event_type: ServiceEventType::Added,
         hostname: "".to_string(),
@@ -67,20 +76,23 @@ fn services_from_record_list(start_record: PDNS_RECORD) -> Result<Service> {
     let mut current_record = start_record;
     while !current_record.is_null() {
         match DnsRecord::try_from(current_record) {
-            Ok(DnsRecord::Ptr(name)) => {
-                if let Some((name, regtype, domain)) = process_name(&name) {
-                    service.name = name;
-                    service.regtype = regtype;
+            Ok(DnsRecord::Ptr(name)) => match split_full_name(&name, regtype) {
+                Some((instance, service_type, domain)) => {
+                    service.name = instance;
+                    service.regtype = service_type;
                     service.domain = domain;
                 }
-            }
+                None => {
+                    error!("Error splitting PTR name {}, skipping it", name);
+                }
+            },
             Ok(DnsRecord::Srv { port, hostname }) => {
                 service.port = port;
                 service.hostname = hostname;
             }
-            Ok(DnsRecord::Txt(hash)) => {
-                if !hash.is_empty() {
-                    service.txt_record = Some(hash);
+            Ok(DnsRecord::Txt(txt)) => {
+                if !txt.is_empty() {
+                    service.txt_record = Some(txt);
                 }
             }
             Ok(DnsRecord::A(_ip)) => {}
@@ -126,27 +138,17 @@ impl TryFrom<PDNS_RECORD> for DnsRecord {
                     (*record).Data.Txt.pStringArray.as_ptr(),
                     (*record).Data.Txt.dwStringCount as _,
                 );
-                let mut hash = HashMap::with_capacity(strings.len());
+                let mut txt = TXTRecord::new();
                 for str_ptr in strings {
                     match U16CStr::from_ptr_str(*str_ptr).to_string() {
-                        Ok(s) => {
-                            let mut split = s.split('=');
-                            match (split.next(), split.next()) {
-                                (Some(k), Some(v)) => {
-                                    hash.insert(k.to_string(), v.to_string());
-                                }
-                                _ => {
-                                    warn!("Failed to get key=value from TXT string: {}", s);
-                                }
-                            }
-                        }
+                        Ok(s) => txt.push_entry(s.as_bytes()),
                         Err(e) => {
                             error!("Error parsing TXT string: {:?}", e);
                         }
                     }
                 }
 
-                Ok(DnsRecord::Txt(hash))
+                Ok(DnsRecord::Txt(txt))
             },
             DNS_TYPE_A => unsafe {
                 let data = (*record).Data.A;
@@ -179,12 +181,18 @@ pub unsafe extern "C" fn browse_callback(status: DWORD, context: PVOID, record:
         error!("Callback has nil context, returning early");
         return;
     }
-    let tx_ptr: *mut SyncSender<Service> = context as _;
-    let tx = &*tx_ptr;
-    match services_from_record_list(record) {
-        Ok(service) => {
+    let context_ptr: *mut BrowseContext = context as _;
+    let context = &*context_ptr;
+    match services_from_record_list(record, &context.regtype) {
+        Ok(mut service) => {
+            // results don't say which interface they came from, but are limited to the one browsed
+            if context.interface_index != 0 {
+                service.interface_index = Some(context.interface_index);
+                service.interface_name = interface_name(context.interface_index);
+            }
+            context.track_txt(&mut service);
             trace!("{:?}", service);
-            match tx.send(service) {
+            match context.tx.send(service) {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Error sending service info: {:?}", e);
@@ -200,8 +208,15 @@ pub unsafe extern "C" fn browse_callback(status: DWORD, context: PVOID, record:
 /// Service browser for DNS-SD services
 pub struct ServiceBrowser {
     cancel: _DNS_SERVICE_CANCEL,
-    context: *mut SyncSender<Service>,
-    receiver: Receiver<Service>,
+    context: *mut BrowseContext,
+    pub(super) receiver: Receiver<Service>,
+    /// Woken as services are sent on to `receiver`
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(super) waker: Arc<ChannelWaker>,
+    /// Delivers services only with `recv_discovered`, as with `with_no_resolve`
+    no_resolve: bool,
+    /// Set once `next_service` has failed with `Error::BrowseOnly`, so iterators end after it
+    browse_only_reported: Cell<bool>,
 }
 impl Drop for ServiceBrowser {
     fn drop(&mut self) {
@@ -221,42 +236,217 @@ impl ServiceBrowser {
             self.context = null_mut();
         }
     }
-    /// Receives any newly discovered services if any
-    pub fn recv_timeout(&self, timeout: Duration) -> Result<Service> {
+    /// Connection status of the browser, the Windows DNS API keeps browsing itself
+    pub fn status(&self) -> ConnectionStatus {
+        ConnectionStatus::Connected
+    }
+    /// Returns services found since the last call without blocking, none if browse-only
+    ///
+    /// The Windows DNS API calls back on its own threads, so there's no socket to watch.
+    pub fn process_ready(&self) -> Vec<Result<Service>> {
+        if self.no_resolve {
+            return Vec::new();
+        }
+        self.receiver.try_iter().map(Ok).collect()
+    }
+    /// Returns a discovered service if any by the timeout, none if there wasn't one yet
+    ///
+    /// Fails with `Error::BrowseOnly` if browsing with `with_no_resolve`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Service>> {
+        if self.no_resolve {
+            return Err(BrowseError::BrowseOnly);
+        }
         match self.receiver.recv_timeout(timeout) {
-            Ok(service) => Ok(service),
-            Err(RecvTimeoutError::Timeout) => Err(BrowseError::Timeout),
-            Err(RecvTimeoutError::Disconnected) => {
-                Err(BrowseError::IoError(IoError::from(ErrorKind::BrokenPipe)))
-            }
+            Ok(service) => Ok(Some(service)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(BrowseError::Stopped),
         }
     }
+    /// True if browsing with `with_no_resolve`
+    pub(crate) fn browse_only(&self) -> bool {
+        self.no_resolve
+    }
+    /// Waits until a service is found, up to the timeout if given, none if there wasn't one by
+    /// then
+    ///
+    /// The Windows DNS API finds services already resolved, so they're only stripped down.
+    pub(crate) fn next_discovered(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DiscoveredService>> {
+        let service = match timeout {
+            Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                Ok(service) => service,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(BrowseError::Stopped),
+            },
+            None => self.receiver.recv().map_err(|_| BrowseError::Stopped)?,
+        };
+        Ok(Some(service.discovered()))
+    }
+    /// Blocks until a service is found, none once the browser's stopped
+    ///
+    /// Fails with `Error::BrowseOnly` once if browse-only, then returns none.
+    pub(crate) fn next_service(&self) -> Option<Result<Service>> {
+        if self.no_resolve {
+            return (!self.browse_only_reported.replace(true))
+                .then_some(Err(BrowseError::BrowseOnly));
+        }
+        self.receiver.recv().ok().map(Ok)
+    }
 }
 pub fn browse(builder: ServiceBrowserBuilder) -> Result<ServiceBrowser> {
-    let name = format!("{}.local", builder.regtype);
+    if builder.runtime.is_some() || builder.manual_processing {
+        trace!("Windows DNS API calls back on its own threads, runtime isn't used");
+    }
+    let name = match &builder.subtype {
+        Some(subtype) => {
+            let subtype = subtype_label(subtype).map_err(BrowseError::InvalidSubtype)?;
+            format!("{}._sub.{}.local", subtype, builder.regtype)
+        }
+        None => format!("{}.local", builder.regtype),
+    };
     let mut name = to_utf16(name);
     let callback = BrowseCallbackUnion {
         pBrowseCallback: Some(browse_callback),
     };
-    let (tx, rx) = sync_channel::<Service>(10);
-    let tx = Box::into_raw(Box::new(tx));
+    let (tx, rx, waker) = waking_channel::<Service>();
+    let interface_index =
+        interface_index(builder.interface.as_ref()).map_err(BrowseError::UnknownInterface)?;
+    let context = Box::into_raw(Box::new(BrowseContext {
+        tx,
+        interface_index,
+        regtype: builder.regtype.clone(),
+        txt_records: Mutex::new(HashMap::new()),
+    }));
     let mut request = _DNS_SERVICE_BROWSE_REQUEST {
         Version: DNS_QUERY_REQUEST_VERSION1,
-        InterfaceIndex: 0,
+        InterfaceIndex: interface_index,
         QueryName: name.as_mut_ptr(),
         __bindgen_anon_1: callback,
-        pQueryContext: tx as _,
+        pQueryContext: context as _,
     };
     unsafe {
         let mut cancel: _DNS_SERVICE_CANCEL = std::mem::zeroed();
         let r = DnsServiceBrowse(&mut request, &mut cancel) as u32;
         if r != DNS_REQUEST_PENDING {
-            return Err(BrowseError::DnsError(r));
+            return Err(BrowseError::dns_status(r));
         }
         Ok(ServiceBrowser {
             cancel,
-            context: tx,
+            context,
             receiver: rx,
+            waker,
+            no_resolve: builder.no_resolve,
+            browse_only_reported: Cell::new(false),
         })
     }
 }
+
+/// Host, port & TXT record of a resolved service
+type Resolution = (String, u16, Option<TXTRecord>);
+
+/// Sends the resolved instance on, owning the context so it's freed once called back
+unsafe extern "C" fn resolve_callback(
+    status: DWORD,
+    context: PVOID,
+    instance: PDNS_SERVICE_INSTANCE,
+) {
+    if context.is_null() {
+        error!("Callback has nil context, returning early");
+    } else {
+        let tx = Box::from_raw(context as *mut WakingSender<Result<Resolution>>);
+        let resolution = if status != 0 {
+            Err(BrowseError::dns_status(status))
+        } else if instance.is_null() {
+            Err(IoError::from(ErrorKind::InvalidData).into())
+        } else {
+            let instance = &*instance;
+            let hostname = if instance.pszHostName.is_null() {
+                String::new()
+            } else {
+                U16CStr::from_ptr_str(instance.pszHostName).to_string_lossy()
+            };
+            let mut txt = TXTRecord::new();
+            for i in 0..instance.dwPropertyCount as usize {
+                let key = U16CStr::from_ptr_str(*instance.keys.add(i)).to_string_lossy();
+                let value = *instance.values.add(i);
+                if value.is_null() {
+                    txt.insert_flag(&key);
+                } else {
+                    txt.insert(&key, U16CStr::from_ptr_str(value).to_string_lossy());
+                }
+            }
+            Ok((
+                hostname,
+                instance.wPort,
+                Some(txt).filter(|txt| !txt.is_empty()),
+            ))
+        };
+        if let Err(e) = tx.send(resolution) {
+            error!("Error sending resolved service: {:?}", e);
+        }
+    }
+    if !instance.is_null() {
+        DnsServiceFreeInstance(instance);
+    }
+}
+
+/// Resolves a discovered service, waiting up to the timeout if given, none if not resolved by
+/// then
+pub fn resolve(
+    discovered: &DiscoveredService,
+    timeout: Option<Duration>,
+) -> Result<Option<Service>> {
+    let mut name = to_utf16(full_name(
+        &discovered.name,
+        &discovered.regtype,
+        &discovered.domain,
+    ));
+    let (tx, rx, _waker) = waking_channel::<Result<Resolution>>();
+    let context = Box::into_raw(Box::new(tx));
+    let mut request = _DNS_SERVICE_RESOLVE_REQUEST {
+        Version: DNS_QUERY_REQUEST_VERSION1,
+        InterfaceIndex: discovered.interface_index,
+        QueryName: name.as_mut_ptr(),
+        pResolveCompletionCallback: Some(resolve_callback),
+        pQueryContext: context as _,
+    };
+    let mut cancel: _DNS_SERVICE_CANCEL = unsafe { std::mem::zeroed() };
+    let r = unsafe { DnsServiceResolve(&mut request, &mut cancel) } as u32;
+    if r != DNS_REQUEST_PENDING {
+        // never called back, so the context is still ours to free
+        _ = unsafe { Box::from_raw(context) };
+        return Err(BrowseError::dns_status(r));
+    }
+    let resolution = match timeout {
+        Some(timeout) => rx.recv_timeout(timeout),
+        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    let (hostname, port, txt_record) = match resolution {
+        Ok(resolution) => resolution?,
+        Err(RecvTimeoutError::Timeout) => {
+            // the callback's still called once cancelled, freeing the context
+            let r = unsafe { DnsServiceResolveCancel(&mut cancel) };
+            if r != 0 {
+                error!("Error canceling resolve: {}", r);
+            }
+            return Ok(None);
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            return Err(IoError::from(ErrorKind::BrokenPipe).into())
+        }
+    };
+    let interface_index = Some(discovered.interface_index).filter(|&index| index != 0);
+    Ok(Some(Service {
+        name: discovered.name.clone(),
+        regtype: discovered.regtype.clone(),
+        interface_index,
+        interface_name: interface_index.and_then(interface_name),
+        domain: discovered.domain.clone(),
+        event_type: discovered.event_type,
+        hostname,
+        port,
+        txt_record,
+    }))
+}
@@ -1,31 +1,32 @@
+use crate::error::{RegistrationError, ServiceErrorKind};
 use crate::ffi::windows as ffi;
 use crate::ffi::windows::{DWORD, PDNS_SERVICE_INSTANCE, PVOID};
-use crate::os::windows::to_utf16;
+use crate::interface::interface_index;
+#[cfg(feature = "async-io")]
+use crate::non_blocking::recv_timeout_async;
+use crate::non_blocking::{waking_channel, ChannelWaker, WakingSender};
+use crate::os::windows::{full_name, split_full_name, to_utf16};
+use crate::record::RecordData;
+use crate::register::{event_channel, EventSender, RegistrationEvent};
+use crate::status::ConnectionStatus;
+use crate::subtype::regtype_with_subtypes;
+use crate::txt::{TXTError, TXTRecord};
 use crate::DNSServiceBuilder;
 use std::convert::TryFrom;
 use std::ffi::OsString;
 use std::fmt;
-use std::io::{Error as IoError, ErrorKind};
+use std::io::Error as IoError;
+use std::net::IpAddr;
 use std::os::windows::ffi::OsStringExt;
 use std::ptr::null_mut;
-use std::sync::mpsc::{sync_channel, SyncSender};
-use std::time::Duration;
-use thiserror::Error;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use widestring::U16CStr;
 use winapi::shared::winerror::DNS_REQUEST_PENDING;
 use winapi::um::winbase::GetComputerNameW;
 
-const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
-
-/// Errors during DNS-SD registration
-#[derive(Debug, Error)]
-pub enum RegistrationError {
-    /// IO Error
-    #[error("IO Error: {0}")]
-    IoError(#[from] IoError),
-    /// Error occurred during registration, non-successful DNS return code
-    #[error("DNS return code error: {0}")]
-    DnsStatusError(DWORD),
-}
+/// Gives up renaming after this many attempts, in case the rename strategy keeps conflicting
+const MAX_RENAME_ATTEMPTS: u32 = 32;
 
 /// Registration result type
 pub type Result<T, E = RegistrationError> = std::result::Result<T, E>;
@@ -40,7 +41,7 @@ struct KeyValues {
 trait DNSServiceExt {
     fn host_name(&self) -> String;
     fn service_name(&self) -> String;
-    fn txt_key_values(&self) -> Option<KeyValues>;
+    fn txt_key_values(&self) -> Result<Option<KeyValues>, TXTError>;
 }
 
 impl DNSServiceExt for DNSServiceBuilder {
@@ -59,24 +60,33 @@ impl DNSServiceExt for DNSServiceBuilder {
             .clone()
             .or(computer_name())
             .unwrap_or_else(|| String::from("Unknown"));
-        format!("{}.{}.local", name, self.regtype)
+        full_name(&name, &self.regtype, "local")
     }
-    fn txt_key_values(&self) -> Option<KeyValues> {
-        let len = self.txt.as_ref()?.len();
-        let mut keys = Vec::with_capacity(len);
-        let mut values = Vec::with_capacity(len);
-        for (key, value) in self.txt.as_ref()?.iter() {
+    fn txt_key_values(&self) -> Result<Option<KeyValues>, TXTError> {
+        let txt = match &self.txt {
+            Some(txt) => txt,
+            None => return Ok(None),
+        };
+        let mut keys = Vec::with_capacity(txt.len());
+        let mut values = Vec::with_capacity(txt.len());
+        for (key, value) in txt.iter() {
+            // DnsServiceConstructInstance only takes nul terminated strings, so key only & binary
+            // values can't be sent without changing them
+            let value = value
+                .as_str()
+                .filter(|value| !value.contains('\0'))
+                .ok_or_else(|| TXTError::UnsupportedValue(key.to_string()))?;
             keys.push(to_utf16(key));
             values.push(to_utf16(value));
         }
         let keys_ptr = keys.iter_mut().map(|k| k.as_mut_ptr()).collect();
         let values_ptr = values.iter_mut().map(|v| v.as_mut_ptr()).collect();
-        Some(KeyValues {
+        Ok(Some(KeyValues {
             keys,
             _values: values,
             keys_ptr,
             values_ptr,
-        })
+        }))
     }
 }
 
@@ -95,26 +105,77 @@ fn computer_name() -> Option<String> {
     None
 }
 
+/// Return code & instance name registered, if given
+type RegisterReply = (DWORD, Option<String>);
+/// Channel the registration callback replies on
+type ReplyReceiver = (Receiver<RegisterReply>, Arc<ChannelWaker>);
+
+/// Callback context replying on the returned receiver, owned by the callback once a request is
+/// pending
+fn reply_context() -> (PVOID, ReplyReceiver) {
+    let (tx, rx, waker) = waking_channel::<RegisterReply>();
+    (Box::into_raw(Box::new(tx)) as _, (rx, waker))
+}
+
+/// Frees the context of a request that failed to start, as it's never called back
+///
+/// # Safety
+/// `context` must be from `reply_context` & not given to a pending request
+unsafe fn free_reply_context(context: PVOID) {
+    _ = Box::from_raw(context as *mut WakingSender<RegisterReply>);
+}
+
 unsafe extern "C" fn register_callback(
     status: DWORD,
     context: PVOID,
     instance: PDNS_SERVICE_INSTANCE,
 ) {
     if !context.is_null() {
-        let tx_ptr: *mut SyncSender<DWORD> = context as _;
-        let tx = &*tx_ptr;
+        // called back once per request, even if nobody's waiting anymore, so it frees the context
+        let tx = Box::from_raw(context as *mut WakingSender<RegisterReply>);
         trace!("Register complete: {} return code", status);
-        tx.send(status).unwrap();
+        let instance_name = if !instance.is_null() && !(*instance).pszInstanceName.is_null() {
+            Some(U16CStr::from_ptr_str((*instance).pszInstanceName).to_string_lossy())
+        } else {
+            None
+        };
+        // nobody's waiting if an async registration was dropped before it completed
+        if let Err(e) = tx.send((status, instance_name)) {
+            error!("Registration reply not sent: {:?}", e);
+        }
     }
     ffi::DnsServiceFreeInstance(instance);
 }
+/// Handle to an additional record on a registered service, which the Windows DNS API doesn't support
+#[derive(Debug)]
+pub struct RecordHandle {
+    _private: (),
+}
+impl RecordHandle {
+    /// Replaces the record's data & TTL, unsupported on Windows
+    pub fn update(&self, _data: &RecordData, _ttl: u32) -> Result<()> {
+        Err(RegistrationError::Unsupported("Updating records"))
+    }
+    /// Removes the record from the service, unsupported on Windows
+    pub fn remove(self) -> Result<()> {
+        Err(RegistrationError::Unsupported("Removing records"))
+    }
+}
+
 /// Opaque type for a registered DNS-SD service, de-registering on drop
 pub struct RegisteredDnsService {
     registered: bool,
     name: String,
     host: String,
-    request: ffi::_DNS_SERVICE_REGISTER_REQUEST,
+    /// Boxed so it can be leaked along with the instance if a request is still pending
+    request: Box<ffi::_DNS_SERVICE_REGISTER_REQUEST>,
+    /// Handle to cancel registering while it's pending
+    cancel: ffi::_DNS_SERVICE_CANCEL,
     service: *mut ffi::_DNS_SERVICE_INSTANCE,
+    /// Parameters registered with, to register again with changes
+    builder: DNSServiceBuilder,
+    event_tx: EventSender,
+    events: Option<Receiver<RegistrationEvent>>,
 }
 impl fmt::Debug for RegisteredDnsService {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -122,17 +183,111 @@ impl fmt::Debug for RegisteredDnsService {
     }
 }
 impl RegisteredDnsService {
-    fn free_context(&mut self) {
-        if !self.request.pQueryContext.is_null() {
-            _ = unsafe { Box::from_raw(self.request.pQueryContext as *mut SyncSender<u32>) };
-            self.request.pQueryContext = null_mut();
+    /// Name the service is registered with
+    pub fn name(&self) -> String {
+        match split_full_name(&self.name, &self.builder.regtype) {
+            Some((instance, _regtype, _domain)) => instance,
+            None => self.name.clone(),
         }
     }
-    fn register(&mut self) -> Result<()> {
+    /// Service type registered, i.e. `_http._tcp.`
+    pub fn regtype(&self) -> String {
+        format!("{}.", self.builder.regtype)
+    }
+    /// Domain the service is registered in, always `local.` with the Windows DNS API
+    pub fn domain(&self) -> String {
+        String::from("local.")
+    }
+    /// Adds a record to the service, unsupported by the Windows DNS API
+    pub fn add_record(&self, data: &RecordData, _ttl: u32) -> Result<RecordHandle> {
+        data.to_bytes()?;
+        Err(RegistrationError::Unsupported("Adding records"))
+    }
+    /// Replaces the TXT record being advertised, unsupported by the Windows DNS API
+    ///
+    /// The Windows DNS API can't update a registration in place & registering again would make
+    /// browsers see the service removed, so the current registration is kept as is.
+    pub fn update_txt(&mut self, txt: &TXTRecord) -> Result<()> {
+        txt.to_bytes()?;
+        Err(RegistrationError::Unsupported("Updating the TXT record"))
+    }
+    /// Connection status of the registration, the Windows DNS API keeps registrations itself
+    pub fn status(&self) -> ConnectionStatus {
         if self.registered {
-            warn!("Service already registered");
+            ConnectionStatus::Connected
+        } else {
+            ConnectionStatus::Stopped
+        }
+    }
+    /// Takes the receiver of lifecycle events, only available once
+    ///
+    /// The Windows DNS API doesn't report conflicts after registering, so there are only events
+    /// while registering. Events are dropped if not received in time.
+    pub fn events(&mut self) -> Option<Receiver<RegistrationEvent>> {
+        self.events.take()
+    }
+    /// Returns lifecycle events since the last call without blocking, none once `events` has
+    /// taken the receiver
+    ///
+    /// The Windows DNS API calls back on its own threads, so there's no socket to watch.
+    pub fn process_ready(&mut self) -> Result<Vec<RegistrationEvent>> {
+        Ok(self
+            .events
+            .as_ref()
+            .map(|events| events.try_iter().collect())
+            .unwrap_or_default())
+    }
+    /// Keeps sending events to the receiver of the service this replaces
+    fn take_events(&mut self, replaced: &mut RegisteredDnsService) {
+        self.event_tx = replaced.event_tx.clone();
+        self.events = replaced.events.take();
+    }
+    /// Unregisters the service, waiting until it's removed
+    pub fn unregister(mut self) -> Result<()> {
+        self.deregister()
+    }
+    fn deregister(&mut self) -> Result<()> {
+        if !self.registered {
             return Ok(());
         }
+        trace!("De-registering service...");
+        self.registered = false;
+        let (context, (rx, _waker)) = reply_context();
+        self.request.pQueryContext = context;
+        let r = unsafe { ffi::DnsServiceDeRegister(self.request.as_mut(), std::ptr::null_mut()) };
+        if r != DNS_REQUEST_PENDING {
+            error!("Failed to de-register service: {}", r);
+            unsafe { free_reply_context(context) };
+            return Err(RegistrationError::dns_status(r as _));
+        }
+        match rx.recv_timeout(self.builder.timeout) {
+            Ok((0, _instance_name)) => Ok(()),
+            Ok((e, _instance_name)) => {
+                error!("De-registration callback returned error: {}", e);
+                Err(RegistrationError::dns_status(e))
+            }
+            Err(_e) => {
+                error!("Timed out waiting for de-registration callback");
+                self.abandon();
+                Err(RegistrationError::Timeout)
+            }
+        }
+    }
+    /// Leaks the request & instance of a request that's still pending, as the Windows DNS API
+    /// may use them until it calls back
+    fn abandon(&mut self) {
+        warn!("Leaking service instance, its request is still pending");
+        self.service = null_mut();
+        let pending = std::mem::replace(&mut self.request, Box::new(*self.request));
+        Box::leak(pending);
+    }
+    /// Starts registering, the callback replies on the returned receiver, none if already
+    /// registered
+    fn start_register(&mut self) -> Result<Option<ReplyReceiver>> {
+        if self.registered {
+            warn!("Service already registered");
+            return Ok(None);
+        }
         trace!(
             "Registering:  name: {} host: {} port: {}",
             self.name,
@@ -140,49 +295,130 @@ impl RegisteredDnsService {
             unsafe { (*self.service).wPort }
         );
 
-        let (tx, rx) = sync_channel::<DWORD>(1);
-        let tx = Box::into_raw(Box::new(tx));
-        self.request.pQueryContext = tx as _;
-        let result = unsafe { ffi::DnsServiceRegister(&mut self.request, std::ptr::null_mut()) };
+        let (context, reply) = reply_context();
+        self.request.pQueryContext = context;
+        let result = unsafe { ffi::DnsServiceRegister(self.request.as_mut(), &mut self.cancel) };
         if result != DNS_REQUEST_PENDING {
             error!("Failed to register: {}", result);
-            self.free_context();
-            return Err(IoError::from_raw_os_error(result as _).into());
+            unsafe { free_reply_context(context) };
+            return Err(RegistrationError::dns_status(result as _));
         }
-
-        match rx.recv_timeout(CALLBACK_TIMEOUT) {
-            Ok(0) => {
+        Ok(Some(reply))
+    }
+    /// Checks the registration callback's reply
+    fn finish_register(&mut self, reply: Result<RegisterReply, RecvTimeoutError>) -> Result<()> {
+        match reply {
+            Ok((0, instance_name)) => {
                 // DNS_RCODE_NOERROR, from: https://docs.microsoft.com/en-us/windows/win32/dns/dns-constants#dns-response-codes
-                self.free_context();
+                if let Some(instance_name) = instance_name {
+                    self.name = instance_name;
+                }
                 self.registered = true;
                 Ok(())
             }
-            Ok(e) => {
+            Ok((e, _instance_name)) => {
                 error!("Registration callback returned error: {}", e);
-                self.free_context();
-                Err(RegistrationError::DnsStatusError(e))
+                Err(RegistrationError::dns_status(e))
             }
             Err(_e) => {
                 error!("Timed out waiting for registration callback");
-                self.free_context();
-                Err(
-                    IoError::new(ErrorKind::TimedOut, "Timed out waiting for async callback")
-                        .into(),
-                )
+                self.cancel_register();
+                Err(RegistrationError::Timeout)
             }
         }
     }
+    /// Cancels registering while it's pending, the request's called back once cancelled so
+    /// what it uses is leaked
+    fn cancel_register(&mut self) {
+        let r = unsafe { ffi::DnsServiceRegisterCancel(&mut self.cancel) };
+        if r != 0 {
+            error!("Error canceling registration: {}", r);
+        }
+        self.abandon();
+    }
+    fn register(&mut self) -> Result<()> {
+        match self.start_register()? {
+            Some((rx, _waker)) => {
+                let reply = rx.recv_timeout(self.builder.timeout);
+                self.finish_register(reply)
+            }
+            None => Ok(()),
+        }
+    }
+    /// Registers like `register`, waiting for the callback without blocking, cancelling
+    /// registering if dropped before then
+    #[cfg(feature = "async-io")]
+    async fn register_async(&mut self) -> Result<()> {
+        let timeout = self.builder.timeout;
+        match self.start_register()? {
+            Some((rx, waker)) => {
+                let pending = PendingRegister(Some(self));
+                let reply = recv_timeout_async(rx, &waker, timeout).await;
+                pending.finish(reply)
+            }
+            None => Ok(()),
+        }
+    }
+    /// Service to register next after a name conflict, failing with it if there's no way to
+    /// rename
+    fn renamed(
+        &mut self,
+        conflict: RegistrationError,
+        base_name: &str,
+        attempt: &mut u32,
+    ) -> Result<RegisteredDnsService> {
+        self.event_tx
+            .send(RegistrationEvent::Conflict { name: self.name() });
+        let mut builder = self.builder.clone();
+        let rename = match builder.rename.take() {
+            Some(rename) if *attempt < MAX_RENAME_ATTEMPTS => rename,
+            _ => return Err(conflict),
+        };
+        *attempt += 1;
+        let name = rename(base_name, *attempt);
+        info!("Service name conflict, renaming to {}", name);
+        builder.name = Some(name);
+        builder.rename = Some(rename);
+        let mut renamed = RegisteredDnsService::try_from(builder)?;
+        renamed.take_events(self);
+        Ok(renamed)
+    }
 }
+/// Registration waited on without blocking, cancelled if dropped before its reply's checked
+#[cfg(feature = "async-io")]
+struct PendingRegister<'a>(Option<&'a mut RegisteredDnsService>);
+#[cfg(feature = "async-io")]
+impl PendingRegister<'_> {
+    fn finish(mut self, reply: Result<RegisterReply, RecvTimeoutError>) -> Result<()> {
+        match self.0.take() {
+            Some(service) => service.finish_register(reply),
+            None => Ok(()),
+        }
+    }
+}
+#[cfg(feature = "async-io")]
+impl Drop for PendingRegister<'_> {
+    fn drop(&mut self) {
+        if let Some(service) = self.0.take() {
+            trace!("Registration dropped while pending, cancelling it");
+            service.cancel_register();
+        }
+    }
+}
+
 impl TryFrom<DNSServiceBuilder> for RegisteredDnsService {
-    type Error = std::io::Error;
-    fn try_from(service: DNSServiceBuilder) -> Result<Self, Self::Error> {
+    type Error = RegistrationError;
+    fn try_from(builder: DNSServiceBuilder) -> Result<Self, Self::Error> {
+        let (event_tx, events) = event_channel();
+        let interface_index = interface_index(builder.interface.as_ref())
+            .map_err(RegistrationError::UnknownInterface)?;
         unsafe {
-            let original_name = service.service_name();
-            let original_host = service.host_name();
+            let original_name = builder.service_name();
+            let original_host = builder.host_name();
             let mut name = to_utf16(&original_name);
             let mut host = to_utf16(&original_host);
 
-            let mut kv_store = service.txt_key_values();
+            let mut kv_store = builder.txt_key_values()?;
             let (property_count, keys_ptr, values_ptr) = match kv_store.as_mut() {
                 Some(kv) => (
                     kv.keys.len(),
@@ -191,13 +427,27 @@ impl TryFrom<DNSServiceBuilder> for RegisteredDnsService {
                 ),
                 None => (0, null_mut() as _, null_mut() as _),
             };
+            // only one address of each type can be given to the DNS API
+            let mut ip4 = builder.host_addresses.iter().find_map(|ip| match ip {
+                IpAddr::V4(ip) => Some(u32::from_le_bytes(ip.octets())),
+                IpAddr::V6(_) => None,
+            });
+            let mut ip6 = builder.host_addresses.iter().find_map(|ip| match ip {
+                IpAddr::V6(ip) => Some(ffi::IP6_ADDRESS {
+                    IP6Byte: ip.octets(),
+                }),
+                IpAddr::V4(_) => None,
+            });
+            if builder.host_addresses.len() > ip4.iter().count() + ip6.iter().count() {
+                warn!("Only the first IPv4 & IPv6 host addresses are registered on Windows");
+            }
             // behavior suggests this copies it's arguments, so we can use pointers to rust stack here
             let service = ffi::DnsServiceConstructInstance(
                 name.as_mut_ptr(),
                 host.as_mut_ptr(),
-                null_mut(),
-                null_mut(),
-                service.port,
+                ip4.as_mut().map_or(null_mut(), |ip| ip as *mut _),
+                ip6.as_mut().map_or(null_mut(), |ip| ip as *mut _),
+                builder.port,
                 0,
                 0,
                 property_count as _,
@@ -207,11 +457,11 @@ impl TryFrom<DNSServiceBuilder> for RegisteredDnsService {
             if service.is_null() {
                 let error = IoError::last_os_error();
                 error!("Failed to create service: {:?}", error);
-                return Err(error);
+                return Err(error.into());
             }
             let request = ffi::_DNS_SERVICE_REGISTER_REQUEST {
                 Version: ffi::DNS_QUERY_REQUEST_VERSION1,
-                InterfaceIndex: 0, // 0 says all interfaces
+                InterfaceIndex: interface_index, // 0 says all interfaces
                 pServiceInstance: service,
                 pRegisterCompletionCallback: Some(register_callback),
                 pQueryContext: null_mut(),
@@ -222,8 +472,12 @@ impl TryFrom<DNSServiceBuilder> for RegisteredDnsService {
                 name: original_name,
                 host: original_host,
                 registered: false,
-                request,
+                request: Box::new(request),
+                cancel: std::mem::zeroed(),
                 service,
+                builder,
+                event_tx,
+                events: Some(events),
             })
         }
     }
@@ -231,12 +485,8 @@ impl TryFrom<DNSServiceBuilder> for RegisteredDnsService {
 
 impl Drop for RegisteredDnsService {
     fn drop(&mut self) {
-        if self.registered {
-            trace!("De-registering service...");
-            let r = unsafe { ffi::DnsServiceDeRegister(&mut self.request, std::ptr::null_mut()) };
-            if r != DNS_REQUEST_PENDING {
-                error!("Failed to de-register service: {}", r);
-            }
+        if let Err(e) = self.deregister() {
+            error!("Error de-registering service: {:?}", e);
         }
 
         if !self.service.is_null() {
@@ -249,8 +499,76 @@ impl Drop for RegisteredDnsService {
 // should be safe to send across threads, just not access across
 unsafe impl Send for RegisteredDnsService {}
 
+/// Validates the builder, returning the name to base renames on
+fn base_name(service: &DNSServiceBuilder) -> Result<String> {
+    // validate with the same rules as the wire format, even though we hand over strings
+    service.txt_bytes()?;
+    if service.host.is_none() && !service.host_addresses.is_empty() {
+        return Err(RegistrationError::MissingHost);
+    }
+    if let Some(domain) = &service.domain {
+        if domain.trim_end_matches('.') != "local" {
+            warn!(
+                "Windows DNS API only registers in local., ignoring domain {}",
+                domain
+            );
+        }
+    }
+    interface_index(service.interface.as_ref()).map_err(RegistrationError::UnknownInterface)?;
+    if service.runtime.is_some() || service.manual_processing {
+        trace!("Windows DNS API calls back on its own threads, runtime isn't used");
+    }
+    if !service.subtypes.is_empty() {
+        regtype_with_subtypes(&service.regtype, &service.subtypes)
+            .map_err(RegistrationError::InvalidSubtype)?;
+        return Err(RegistrationError::Unsupported("Registering subtypes"));
+    }
+    Ok(service
+        .name
+        .clone()
+        .or_else(computer_name)
+        .unwrap_or_else(|| String::from("Unknown")))
+}
+
 pub fn register_service(service: DNSServiceBuilder) -> Result<RegisteredDnsService> {
+    let base_name = base_name(&service)?;
     let mut service = RegisteredDnsService::try_from(service)?;
-    service.register()?;
-    Ok(service)
+    // the Windows DNS API doesn't rename on its own, or report conflicts after registering, so
+    // only conflicts while registering can be renamed
+    let mut attempt = 1;
+    loop {
+        match service.register() {
+            Err(e) if e.service_error_kind() == Some(ServiceErrorKind::NameConflict) => {
+                service = service.renamed(e, &base_name, &mut attempt)?
+            }
+            result => {
+                result?;
+                service.event_tx.send(RegistrationEvent::Registered {
+                    name: service.name(),
+                });
+                return Ok(service);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async-io")]
+pub async fn register_service_async(service: DNSServiceBuilder) -> Result<RegisteredDnsService> {
+    let base_name = base_name(&service)?;
+    let mut service = RegisteredDnsService::try_from(service)?;
+    let mut attempt = 1;
+    loop {
+        match service.register_async().await {
+            Err(e) if e.service_error_kind() == Some(ServiceErrorKind::NameConflict) => {
+                service = service.renamed(e, &base_name, &mut attempt)?
+            }
+            result => {
+                result?;
+                service.event_tx.send(RegistrationEvent::Registered {
+                    name: service.name(),
+                });
+                return Ok(service);
+            }
+        }
+    }
 }
@@ -0,0 +1,23 @@
+//! Runtime handle, the Windows DNS API already calls back on its own thread pool
+
+use crate::error::Error;
+use crate::status::ConnectionStatus;
+
+/// Runs browsers & registrations together, instead of a thread each
+///
+/// The Windows DNS API already calls back on its own thread pool, so there's nothing to share
+/// & this only exists so the same code builds on every platform.
+#[derive(Debug, Clone)]
+pub struct Runtime {
+    _private: (),
+}
+impl Runtime {
+    /// Creates the runtime
+    pub fn new() -> Result<Runtime, Error> {
+        Ok(Runtime { _private: () })
+    }
+    /// Connection status, always connected as the Windows DNS API has no daemon connection
+    pub fn status(&self) -> ConnectionStatus {
+        ConnectionStatus::Connected
+    }
+}
@@ -0,0 +1,49 @@
+//! Browser as an async stream
+
+use super::browse::ServiceBrowser;
+use crate::browse::{Result, Service};
+use crate::status::ConnectionStatus;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::mpsc::TryRecvError;
+use std::task::{Context, Poll};
+
+/// Browser as a `Stream` of services, woken as the Windows DNS API calls back with them
+///
+/// Created with `ServiceBrowser::into_stream`, browsing stops when it's dropped. Services are
+/// queued until polled, so it's cancel safe.
+pub struct ServiceStream {
+    browser: ServiceBrowser,
+}
+
+impl ServiceBrowser {
+    /// Turns the browser into a `Stream` of services, for use with tokio
+    pub fn into_stream(self) -> ServiceStream {
+        ServiceStream { browser: self }
+    }
+}
+
+impl ServiceStream {
+    /// Connection status of the browser, the Windows DNS API keeps browsing itself
+    pub fn status(&self) -> ConnectionStatus {
+        self.browser.status()
+    }
+}
+
+impl Stream for ServiceStream {
+    type Item = Result<Service>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // browse-only services are received with `recv_discovered` instead, so this fails once
+        if self.browser.browse_only() {
+            return Poll::Ready(self.browser.next_service());
+        }
+        // registered first, so a service sent after checking still wakes the task
+        self.browser.waker.register(cx.waker());
+        match self.browser.receiver.try_recv() {
+            Ok(service) => Poll::Ready(Some(Ok(service))),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Additional resource records attached to a registered service
+
+use crate::txt::{TXTError, TXTRecord};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use thiserror::Error;
+
+/// Longest character string, as in HINFO & TXT data
+const MAX_STRING_LEN: usize = 255;
+
+/// Error encoding record data
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    /// TXT record data couldn't be encoded
+    #[error("Invalid TXT record: {0}")]
+    Txt(#[from] TXTError),
+    /// Character string is over 255 bytes
+    #[error("Character string of {0} bytes is over 255 bytes")]
+    StringTooLong(usize),
+    /// Record data is over 65535 bytes
+    #[error("Record data of {0} bytes is over 65535 bytes")]
+    TooLong(usize),
+    /// Record updated with data of a different type than it was added with
+    #[error("Record type {found} doesn't match type {expected} it was added with")]
+    TypeMismatch {
+        /// Type record was added with
+        expected: u16,
+        /// Type of data given
+        found: u16,
+    },
+}
+
+/// Typed data for a resource record, encoded to the wire format when added
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    /// IPv4 address record
+    A(Ipv4Addr),
+    /// IPv6 address record
+    Aaaa(Ipv6Addr),
+    /// Host info record, with CPU & OS strings
+    Hinfo {
+        /// CPU type, i.e. `ARM64`
+        cpu: String,
+        /// Operating system, i.e. `LINUX`
+        os: String,
+    },
+    /// Null record of arbitrary bytes
+    Null(Vec<u8>),
+    /// Text record
+    Txt(TXTRecord),
+    /// Any other type, with data already encoded
+    Raw {
+        /// Resource record type, i.e. 13 for HINFO
+        rrtype: u16,
+        /// Encoded record data
+        data: Vec<u8>,
+    },
+}
+
+impl RecordData {
+    /// Resource record type of the data
+    pub fn rrtype(&self) -> u16 {
+        match self {
+            RecordData::A(_) => 1,
+            RecordData::Aaaa(_) => 28,
+            RecordData::Hinfo { .. } => 13,
+            RecordData::Null(_) => 10,
+            RecordData::Txt(_) => 16,
+            RecordData::Raw { rrtype, .. } => *rrtype,
+        }
+    }
+    /// Encodes the data in its wire format
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RecordError> {
+        let data = match self {
+            RecordData::A(ip) => ip.octets().to_vec(),
+            RecordData::Aaaa(ip) => ip.octets().to_vec(),
+            RecordData::Hinfo { cpu, os } => {
+                let mut data = Vec::with_capacity(cpu.len() + os.len() + 2);
+                for string in &[cpu, os] {
+                    if string.len() > MAX_STRING_LEN {
+                        return Err(RecordError::StringTooLong(string.len()));
+                    }
+                    data.push(string.len() as u8);
+                    data.extend_from_slice(string.as_bytes());
+                }
+                data
+            }
+            RecordData::Null(data) => data.clone(),
+            RecordData::Txt(txt) => {
+                let mut data = txt.to_bytes()?;
+                // an empty TXT record is still a single empty string
+                if data.is_empty() {
+                    data.push(0);
+                }
+                data
+            }
+            RecordData::Raw { data, .. } => data.clone(),
+        };
+        if data.len() > u16::MAX as usize {
+            return Err(RecordError::TooLong(data.len()));
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_data() {
+        let hinfo = RecordData::Hinfo {
+            cpu: "ARM64".into(),
+            os: "LINUX".into(),
+        };
+        assert_eq!(hinfo.rrtype(), 13);
+        assert_eq!(hinfo.to_bytes().unwrap(), b"\x05ARM64\x05LINUX");
+        let a = RecordData::A(Ipv4Addr::new(192, 168, 1, 2));
+        assert_eq!(a.to_bytes().unwrap(), vec![192, 168, 1, 2]);
+        assert_eq!(
+            RecordData::Txt(TXTRecord::new()).to_bytes().unwrap(),
+            vec![0]
+        );
+        let long = RecordData::Hinfo {
+            cpu: "a".repeat(256),
+            os: String::new(),
+        };
+        assert_eq!(long.to_bytes(), Err(RecordError::StringTooLong(256)));
+        let raw = RecordData::Raw {
+            rrtype: 99,
+            data: vec![1, 2],
+        };
+        assert_eq!((raw.rrtype(), raw.to_bytes().unwrap()), (99, vec![1, 2]));
+    }
+}
@@ -1,28 +1,161 @@
-use crate::os::{register_service, RegisteredDnsService, RegistrationError};
-use std::collections::HashMap;
+use crate::error::{RegistrationError, ServiceErrorKind};
+use crate::interface::Interface;
+#[cfg(feature = "async-io")]
+use crate::os::register_service_async;
+use crate::os::{register_service, RegisteredDnsService, Runtime};
+use crate::service_type::RegType;
+use crate::txt::{TXTError, TXTRecord, TXTValue};
+use std::net::IpAddr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::Duration;
 pub type Result<T, E = RegistrationError> = std::result::Result<T, E>;
 
+/// How long to wait for the daemon to reply by default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Events buffered before new ones are dropped, if the receiver isn't keeping up
+const EVENT_CAPACITY: usize = 16;
+
+/// Lifecycle event of a registered service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationEvent {
+    /// Service was registered with the given name
+    Registered {
+        /// Name registered with
+        name: String,
+    },
+    /// Service was renamed after a name conflict
+    Renamed {
+        /// Name registered with before
+        from: String,
+        /// Name registered with now
+        to: String,
+    },
+    /// Service name conflicts with another service, it's renamed after if there's a way to
+    Conflict {
+        /// Name that conflicted
+        name: String,
+    },
+    /// Connection to the mDNS daemon was lost, the service is no longer advertised
+    DaemonLost,
+    /// Service was registered again after the daemon connection was lost
+    Reregistered {
+        /// Name registered with
+        name: String,
+    },
+    /// Processing failed for a reason other than losing the daemon, the service is no longer
+    /// advertised & isn't registered again
+    Failed {
+        /// Decoded kind of the daemon error, if it was one
+        kind: Option<ServiceErrorKind>,
+        /// Error as displayed
+        message: String,
+    },
+}
+
+impl RegistrationEvent {
+    pub(crate) fn failed(error: &RegistrationError) -> Self {
+        RegistrationEvent::Failed {
+            kind: error.service_error_kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Sends registration events, dropping them if the receiver isn't keeping up or is gone
+#[derive(Clone)]
+pub(crate) struct EventSender(SyncSender<RegistrationEvent>);
+impl EventSender {
+    pub(crate) fn send(&self, event: RegistrationEvent) {
+        if let Err(e) = self.0.try_send(event) {
+            trace!("Registration event not sent: {:?}", e);
+        }
+    }
+}
+pub(crate) fn event_channel() -> (EventSender, Receiver<RegistrationEvent>) {
+    let (tx, rx) = sync_channel(EVENT_CAPACITY);
+    (EventSender(tx), rx)
+}
+
+/// Picks a new service name after a conflict, given the requested name & attempt number
+pub(crate) type RenameStrategy = Arc<dyn Fn(&str, u32) -> String + Send + Sync>;
+
+/// Validates & encodes a TXT record, failing if over `limit` or warning if over the recommended size
+pub(crate) fn encode_txt(txt: &TXTRecord, limit: Option<usize>) -> Result<Vec<u8>, TXTError> {
+    let txt = txt.to_bytes()?;
+    match limit {
+        Some(max) if txt.len() > max => {
+            return Err(TXTError::RecordTooLong {
+                len: txt.len(),
+                max,
+            })
+        }
+        None if txt.len() > TXTRecord::RECOMMENDED_MAX_LEN => {
+            warn!(
+                "TXT record is {} bytes, over the recommended {} bytes for a single packet",
+                txt.len(),
+                TXTRecord::RECOMMENDED_MAX_LEN
+            );
+        }
+        _ => {}
+    }
+    Ok(txt)
+}
+
 /// Builder for creating a new DNSService for registration purposes
+#[derive(Clone)]
 pub struct DNSServiceBuilder {
+    service_type: RegType,
+    /// Service type without subtypes, set from `service_type` when registering
     pub(crate) regtype: String,
     pub(crate) name: Option<String>,
     pub(crate) domain: Option<String>,
     pub(crate) host: Option<String>,
     pub(crate) port: u16,
-    pub(crate) txt: Option<HashMap<String, String>>,
+    pub(crate) txt: Option<TXTRecord>,
+    pub(crate) txt_size_limit: Option<usize>,
+    pub(crate) subtypes: Vec<String>,
+    pub(crate) interface: Option<Interface>,
+    pub(crate) no_auto_rename: bool,
+    pub(crate) rename: Option<RenameStrategy>,
+    pub(crate) host_addresses: Vec<IpAddr>,
+    pub(crate) runtime: Option<Runtime>,
+    pub(crate) manual_processing: bool,
+    pub(crate) timeout: Duration,
 }
 impl DNSServiceBuilder {
-    /// Starts a new service builder with a given type (i.e. _http._tcp)
-    pub fn new(regtype: &str, port: u16) -> DNSServiceBuilder {
+    /// Starts a new service builder with a given type (i.e. _http._tcp), as a string or
+    /// `ServiceType`
+    ///
+    /// A string is validated when registering, failing with `InvalidServiceType` if it isn't a
+    /// valid service type. Any subtypes in it are registered along with those given to
+    /// `with_subtype`.
+    pub fn new<T: Into<RegType>>(regtype: T, port: u16) -> DNSServiceBuilder {
         DNSServiceBuilder {
-            regtype: String::from(regtype),
+            service_type: regtype.into(),
+            regtype: String::new(),
             name: None,
             domain: None,
             host: None,
             port,
             txt: None,
+            txt_size_limit: None,
+            subtypes: Vec::new(),
+            interface: None,
+            no_auto_rename: false,
+            rename: None,
+            host_addresses: Vec::new(),
+            runtime: None,
+            manual_processing: false,
+            timeout: DEFAULT_TIMEOUT,
         }
     }
+    /// Starts a new service builder with a type registered as is, without validating it
+    ///
+    /// For legacy types RFC 6335 doesn't allow, i.e. with underscores like `_my_type._tcp`.
+    pub fn new_unchecked(regtype: &str, port: u16) -> DNSServiceBuilder {
+        DNSServiceBuilder::new(RegType::unchecked(regtype), port)
+    }
 
     /// Name to use for service, defaults to hostname
     pub fn with_name(mut self, name: &str) -> DNSServiceBuilder {
@@ -42,20 +175,149 @@ impl DNSServiceBuilder {
         self
     }
 
-    /// Includes a TXT record for the service
-    pub fn with_txt_record(mut self, txt: HashMap<String, String>) -> DNSServiceBuilder {
+    /// Fails registration with `ServiceErrorKind::NameConflict` if the name is taken, instead of
+    /// the service being renamed automatically
+    pub fn with_no_auto_rename(mut self) -> DNSServiceBuilder {
+        self.no_auto_rename = true;
+        self
+    }
+
+    /// Renames the service with `rename` if the name is taken, i.e. `|name, n| format!("{} ({})", name, n)`
+    ///
+    /// `rename` is given the requested name & an attempt number starting at 2, & keeps being
+    /// applied to conflicts after registration succeeded.
+    pub fn with_rename_strategy<F>(mut self, rename: F) -> DNSServiceBuilder
+    where
+        F: Fn(&str, u32) -> String + Send + Sync + 'static,
+    {
+        self.rename = Some(Arc::new(rename));
+        self
+    }
+
+    /// Only advertises the service on the given interface, by index or OS name (i.e. `eth0`)
+    ///
+    /// Defaults to all interfaces, names are resolved on registration.
+    pub fn with_interface<I: Into<Interface>>(mut self, interface: I) -> DNSServiceBuilder {
+        self.interface = Some(interface.into());
+        self
+    }
+
+    /// Also advertises the service under a subtype (i.e. `_printer`), can be called for several
+    ///
+    /// Subtypes are validated on registration, failing if not a valid label.
+    pub fn with_subtype(mut self, subtype: &str) -> DNSServiceBuilder {
+        self.subtypes.push(String::from(subtype));
+        self
+    }
+
+    /// Address of the host given to `with_host`, registered as its A or AAAA record so a service
+    /// can be advertised on behalf of another device, can be called for several
+    ///
+    /// Avahi's dns_sd compat layer on Linux can't register host records, so registration fails
+    /// with `ServiceErrorKind::Unsupported`.
+    pub fn with_host_address(mut self, address: IpAddr) -> DNSServiceBuilder {
+        self.host_addresses.push(address);
+        self
+    }
+
+    /// Processes replies on a shared runtime instead of a thread for this service
+    ///
+    /// Avahi's dns_sd compat layer on Linux can't share a connection, so a runtime can't be
+    /// created there.
+    pub fn with_runtime(mut self, runtime: &Runtime) -> DNSServiceBuilder {
+        self.runtime = Some(runtime.clone());
+        self
+    }
+
+    /// Processes replies only when `process_ready` is called instead of on a thread, for event
+    /// loops watching the service's socket
+    ///
+    /// Ignored with `with_runtime`, & by the Windows DNS API which calls back on its own threads.
+    pub fn with_manual_processing(mut self) -> DNSServiceBuilder {
+        self.manual_processing = true;
+        self
+    }
+
+    /// How long to wait for the daemon to confirm registration before failing with `Timeout`,
+    /// defaults to 10 seconds
+    pub fn with_timeout(mut self, timeout: Duration) -> DNSServiceBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Includes a TXT record for the service, accepts a `TXTRecord` or a `HashMap<String, String>`
+    pub fn with_txt_record<T: Into<TXTRecord>>(mut self, txt: T) -> DNSServiceBuilder {
+        self.txt = Some(txt.into());
+        self
+    }
+    /// Adds key & value to TXT, creating a record if none yet
+    pub fn with_key_value(self, key: String, value: String) -> DNSServiceBuilder {
+        self.with_txt_value(&key, value)
+    }
+    /// Adds key & value to TXT, value may be binary or empty, creating a record if none yet
+    ///
+    /// The Windows DNS API only takes string values, binary values fail registering there.
+    pub fn with_txt_value<V: Into<TXTValue>>(mut self, key: &str, value: V) -> DNSServiceBuilder {
+        let mut txt = self.txt.take().unwrap_or_default();
+        txt.insert(key, value);
         self.txt = Some(txt);
         self
     }
-    /// Adds key & value to TXT, creating a map if none yet
-    pub fn with_key_value(mut self, key: String, value: String) -> DNSServiceBuilder {
-        let mut kv = self.txt.take().unwrap_or_default();
-        kv.insert(key, value);
-        self.txt = Some(kv);
+    /// Adds a key only (boolean) attribute to TXT, creating a record if none yet
+    ///
+    /// Not supported by the Windows DNS API, where registering fails.
+    pub fn with_key(mut self, key: &str) -> DNSServiceBuilder {
+        let mut txt = self.txt.take().unwrap_or_default();
+        txt.insert_flag(key);
+        self.txt = Some(txt);
         self
     }
+    /// Fails registration if the encoded TXT record is over `limit` bytes
+    ///
+    /// Without a limit, records over `TXTRecord::RECOMMENDED_MAX_LEN` only log a warning as they
+    /// may not fit in a single packet.
+    pub fn with_txt_size_limit(mut self, limit: usize) -> DNSServiceBuilder {
+        self.txt_size_limit = Some(limit);
+        self
+    }
+    /// Host as a fully qualified name, i.e. `device` becomes `device.local.`
+    pub(crate) fn fqdn_host(&self) -> Option<String> {
+        let host = self.host.as_ref()?;
+        if host.ends_with('.') {
+            Some(host.clone())
+        } else if host.contains('.') {
+            Some(format!("{}.", host))
+        } else {
+            let domain = self.domain.as_deref().unwrap_or("local");
+            Some(format!("{}.{}.", host, domain.trim_end_matches('.')))
+        }
+    }
+    /// Validates & encodes the TXT record if any, applying the size limit
+    pub(crate) fn txt_bytes(&self) -> Result<Option<Vec<u8>>, TXTError> {
+        self.txt
+            .as_ref()
+            .map(|txt| encode_txt(txt, self.txt_size_limit))
+            .transpose()
+    }
+    /// Validates the service type, moving any subtypes given in it over to the others
+    fn split_subtypes(&mut self) -> Result<()> {
+        let (regtype, subtypes) = self.service_type.split()?;
+        self.regtype = regtype;
+        self.subtypes.splice(0..0, subtypes);
+        Ok(())
+    }
     /// Registers service, advertising it on the network
-    pub fn register(self) -> Result<RegisteredDnsService> {
+    pub fn register(mut self) -> Result<RegisteredDnsService> {
+        self.split_subtypes()?;
         register_service(self)
     }
+    /// Registers service like `register`, waiting for the daemon without blocking the executor
+    ///
+    /// Works on any executor, dropping the future before it completes cancels registering the
+    /// service.
+    #[cfg(feature = "async-io")]
+    pub async fn register_async(mut self) -> Result<RegisteredDnsService> {
+        self.split_subtypes()?;
+        register_service_async(self).await
+    }
 }
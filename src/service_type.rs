@@ -0,0 +1,307 @@
+//! Service types, i.e. `_http._tcp`, validated by the rules of RFC 6335
+
+use crate::subtype::subtype_label;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Longest service name RFC 6335 allows
+const MAX_NAME_LEN: usize = 15;
+/// Meta-query for the service types on the network, browsable though it isn't a service type
+const META_QUERY: &str = "_services._dns-sd._udp";
+
+/// Error parsing or building a service type
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ServiceTypeError {
+    /// Service type doesn't have both a service name & protocol, i.e. `_http` or `http._tcp`
+    #[error("Service type {0} isn't in the form _service._tcp or _service._udp")]
+    Malformed(String),
+    /// Service name isn't 1-15 letters, digits & hyphens, with a letter & no leading, trailing or
+    /// double hyphens
+    #[error("Invalid service name: {0}")]
+    InvalidName(String),
+    /// Protocol isn't `_tcp` or `_udp`
+    #[error("Invalid protocol {0}, must be _tcp or _udp")]
+    InvalidProtocol(String),
+    /// Subtype isn't a valid label
+    #[error("Invalid subtype: {0}")]
+    InvalidSubtype(String),
+}
+
+/// Transport protocol of a service type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceProtocol {
+    /// `_tcp`
+    Tcp,
+    /// `_udp`, used for any protocol other than TCP
+    Udp,
+}
+impl fmt::Display for ServiceProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceProtocol::Tcp => f.write_str("_tcp"),
+            ServiceProtocol::Udp => f.write_str("_udp"),
+        }
+    }
+}
+impl FromStr for ServiceProtocol {
+    type Err = ServiceTypeError;
+    fn from_str(protocol: &str) -> Result<Self, Self::Err> {
+        match protocol.to_ascii_lowercase().as_str() {
+            "_tcp" => Ok(ServiceProtocol::Tcp),
+            "_udp" => Ok(ServiceProtocol::Udp),
+            _ => Err(ServiceTypeError::InvalidProtocol(protocol.to_string())),
+        }
+    }
+}
+
+/// Service type with a validated service name, protocol & any subtypes
+///
+/// Parses `_http._tcp`, the `_printer._sub._http._tcp` subtype form & the comma separated
+/// `_http._tcp,_printer` form dns_sd uses, which it's displayed as.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServiceType {
+    name: String,
+    protocol: ServiceProtocol,
+    subtypes: Vec<String>,
+}
+
+/// Validates a service name by the rules of RFC 6335, without its leading underscore
+fn validate_name(name: &str) -> Result<(), ServiceTypeError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && name.chars().any(|c| c.is_ascii_alphabetic())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !name.contains("--");
+    if valid {
+        Ok(())
+    } else {
+        Err(ServiceTypeError::InvalidName(name.to_string()))
+    }
+}
+
+impl ServiceType {
+    /// Creates a service type from a service name, with or without its leading underscore
+    pub fn new(name: &str, protocol: ServiceProtocol) -> Result<Self, ServiceTypeError> {
+        let name = name.strip_prefix('_').unwrap_or(name);
+        validate_name(name)?;
+        Ok(ServiceType {
+            name: name.to_string(),
+            protocol,
+            subtypes: Vec::new(),
+        })
+    }
+    /// Adds a subtype, i.e. `_printer`, with or without its leading underscore
+    pub fn with_subtype(mut self, subtype: &str) -> Result<Self, ServiceTypeError> {
+        let subtype = subtype_label(subtype).map_err(ServiceTypeError::InvalidSubtype)?;
+        self.subtypes.push(subtype);
+        Ok(self)
+    }
+    /// Service name without its leading underscore, i.e. `http`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Transport protocol
+    pub fn protocol(&self) -> ServiceProtocol {
+        self.protocol
+    }
+    /// Subtypes with their leading underscores, i.e. `_printer`
+    pub fn subtypes(&self) -> &[String] {
+        &self.subtypes
+    }
+    /// Service type without subtypes, i.e. `_http._tcp`
+    pub fn regtype(&self) -> String {
+        format!("_{}.{}", self.name, self.protocol)
+    }
+}
+
+/// Service type given to a builder, a `ServiceType` or a string validated when registering or
+/// browsing
+///
+/// Made from a `ServiceType`, `String` or `&str`, or unvalidated with the builders'
+/// `new_unchecked`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegType(RegTypeInner);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RegTypeInner {
+    Typed(ServiceType),
+    /// Parsed as a `ServiceType` when used, unless it's the meta-query
+    Str(String),
+    /// Used as is, for legacy types RFC 6335 doesn't allow
+    Unchecked(String),
+}
+
+impl RegType {
+    /// Service type used as is without validating it
+    pub(crate) fn unchecked(regtype: &str) -> Self {
+        RegType(RegTypeInner::Unchecked(regtype.to_string()))
+    }
+    /// Splits into the service type & any subtypes, failing if a string isn't a valid service type
+    pub(crate) fn split(&self) -> Result<(String, Vec<String>), ServiceTypeError> {
+        let service_type = match &self.0 {
+            RegTypeInner::Typed(service_type) => service_type,
+            RegTypeInner::Str(regtype) => {
+                let base = regtype.strip_suffix('.').unwrap_or(regtype);
+                if base.eq_ignore_ascii_case(META_QUERY) {
+                    return Ok((regtype.clone(), Vec::new()));
+                }
+                let service_type: ServiceType = regtype.parse()?;
+                return Ok((service_type.regtype(), service_type.subtypes));
+            }
+            RegTypeInner::Unchecked(regtype) => return Ok((regtype.clone(), Vec::new())),
+        };
+        Ok((service_type.regtype(), service_type.subtypes.clone()))
+    }
+}
+impl fmt::Display for RegType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            RegTypeInner::Typed(service_type) => service_type.fmt(f),
+            RegTypeInner::Str(regtype) | RegTypeInner::Unchecked(regtype) => f.write_str(regtype),
+        }
+    }
+}
+impl From<ServiceType> for RegType {
+    fn from(service_type: ServiceType) -> Self {
+        RegType(RegTypeInner::Typed(service_type))
+    }
+}
+impl From<&ServiceType> for RegType {
+    fn from(service_type: &ServiceType) -> Self {
+        RegType(RegTypeInner::Typed(service_type.clone()))
+    }
+}
+impl From<String> for RegType {
+    fn from(regtype: String) -> Self {
+        RegType(RegTypeInner::Str(regtype))
+    }
+}
+impl From<&String> for RegType {
+    fn from(regtype: &String) -> Self {
+        RegType(RegTypeInner::Str(regtype.clone()))
+    }
+}
+impl From<&str> for RegType {
+    fn from(regtype: &str) -> Self {
+        RegType(RegTypeInner::Str(regtype.to_string()))
+    }
+}
+
+impl FromStr for ServiceType {
+    type Err = ServiceTypeError;
+    fn from_str(regtype: &str) -> Result<Self, Self::Err> {
+        let mut parts = regtype.split(',');
+        let base = parts.next().unwrap_or_default();
+        let labels: Vec<&str> = base.strip_suffix('.').unwrap_or(base).split('.').collect();
+        let malformed = || ServiceTypeError::Malformed(regtype.to_string());
+        let (subtype, name, protocol) = match labels.as_slice() {
+            [name, protocol] => (None, name, protocol),
+            [subtype, sub, name, protocol] if sub.eq_ignore_ascii_case("_sub") => {
+                (Some(subtype), name, protocol)
+            }
+            _ => return Err(malformed()),
+        };
+        let name = name.strip_prefix('_').ok_or_else(malformed)?;
+        let mut service_type = ServiceType::new(name, protocol.parse()?)?;
+        for subtype in subtype.into_iter().copied().chain(parts) {
+            service_type = service_type.with_subtype(subtype)?;
+        }
+        Ok(service_type)
+    }
+}
+
+impl fmt::Display for ServiceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "_{}.{}", self.name, self.protocol)?;
+        for subtype in &self.subtypes {
+            write!(f, ",{}", subtype)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ServiceType> for String {
+    fn from(service_type: ServiceType) -> Self {
+        service_type.to_string()
+    }
+}
+impl From<&ServiceType> for String {
+    fn from(service_type: &ServiceType) -> Self {
+        service_type.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_service_types() {
+        let http: ServiceType = "_http._tcp".parse().unwrap();
+        assert_eq!(http.name(), "http");
+        assert_eq!(http.protocol(), ServiceProtocol::Tcp);
+        assert_eq!(http.to_string(), "_http._tcp");
+        assert_eq!("_http._tcp.".parse(), Ok(http));
+
+        let printer: ServiceType = "_printer._sub._ipp._tcp".parse().unwrap();
+        assert_eq!(printer.subtypes(), &["_printer".to_string()]);
+        assert_eq!(printer.regtype(), "_ipp._tcp");
+        assert_eq!(printer.to_string(), "_ipp._tcp,_printer");
+        assert_eq!(printer.to_string().parse(), Ok(printer));
+        assert_eq!(
+            "_http._tcp,_a,_b"
+                .parse::<ServiceType>()
+                .unwrap()
+                .subtypes(),
+            &["_a".to_string(), "_b".to_string()]
+        );
+    }
+
+    #[test]
+    fn invalid_service_types() {
+        assert_eq!(
+            "_http.tcp".parse::<ServiceType>(),
+            Err(ServiceTypeError::InvalidProtocol("tcp".to_string()))
+        );
+        assert_eq!(
+            "_http".parse::<ServiceType>(),
+            Err(ServiceTypeError::Malformed("_http".to_string()))
+        );
+        assert_eq!(
+            "http._tcp".parse::<ServiceType>(),
+            Err(ServiceTypeError::Malformed("http._tcp".to_string()))
+        );
+        assert!(ServiceType::new("a-very-long-name", ServiceProtocol::Tcp).is_err());
+        assert!(ServiceType::new("a-long-name-ok", ServiceProtocol::Tcp).is_ok());
+        for name in &["", "123", "-http", "http-", "ht--tp", "ht_tp"] {
+            assert_eq!(
+                ServiceType::new(name, ServiceProtocol::Udp),
+                Err(ServiceTypeError::InvalidName(name.to_string()))
+            );
+        }
+        assert!("_http._tcp,_a.b".parse::<ServiceType>().is_err());
+    }
+
+    #[test]
+    fn split_regtypes() {
+        let split = |regtype: RegType| regtype.split();
+        assert_eq!(
+            split("_printer._sub._ipp._tcp".into()),
+            Ok(("_ipp._tcp".to_string(), vec!["_printer".to_string()]))
+        );
+        assert_eq!(
+            split("_services._dns-sd._udp.".into()),
+            Ok(("_services._dns-sd._udp.".to_string(), Vec::new()))
+        );
+        for regtype in &["_my_legacy_type._tcp", "_http.tcp"] {
+            assert!(split((*regtype).into()).is_err());
+            assert_eq!(
+                split(RegType::unchecked(regtype)),
+                Ok((regtype.to_string(), Vec::new()))
+            );
+        }
+    }
+}
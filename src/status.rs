@@ -0,0 +1,65 @@
+//! Connection status of registrations & browsers to the mDNS daemon
+
+use std::time::Duration;
+
+/// First delay before reconnecting to the daemon
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection status of a registration or browser to the mDNS daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Connected to the daemon, service is advertised or being browsed for
+    Connected,
+    /// Connection to the daemon was lost, trying to establish it again with backoff
+    Reconnecting {
+        /// Reconnection attempt, starting at 1
+        attempt: u32,
+    },
+    /// Unregistered or stopped
+    Stopped,
+}
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    attempt: u32,
+    delay: Duration,
+}
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Backoff {
+            attempt: 0,
+            delay: INITIAL_BACKOFF,
+        }
+    }
+    /// Starts the next attempt, returning the delay to wait before it
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.attempt += 1;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+    /// Current attempt, starting at 1 after the first delay
+    pub(crate) fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next_delay(), INITIAL_BACKOFF);
+        assert_eq!(backoff.next_delay(), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff.attempt(), 2);
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,64 @@
+//! DNS-SD service subtypes, i.e. `_printer` in `_printer._sub._http._tcp`
+
+/// Longest label DNS allows
+pub(crate) const MAX_LABEL_LEN: usize = 63;
+
+/// Validates a subtype label, returning it with the leading underscore subtypes conventionally have
+///
+/// Fails with the given subtype if it's empty, too long or has characters that would be read as
+/// label or subtype separators.
+pub(crate) fn subtype_label(subtype: &str) -> Result<String, String> {
+    let label = if subtype.starts_with('_') {
+        subtype.to_string()
+    } else {
+        format!("_{}", subtype)
+    };
+    let valid = label.len() > 1
+        && label.len() <= MAX_LABEL_LEN
+        && !label
+            .chars()
+            .any(|c| c == '.' || c == ',' || c == '\\' || c.is_control());
+    if valid {
+        Ok(label)
+    } else {
+        Err(subtype.to_string())
+    }
+}
+
+/// Validates subtypes & appends them to the regtype in the comma separated form dns_sd uses,
+/// i.e. `_http._tcp,_printer,_scanner`
+pub(crate) fn regtype_with_subtypes(regtype: &str, subtypes: &[String]) -> Result<String, String> {
+    let mut regtype = regtype.to_string();
+    for subtype in subtypes {
+        regtype.push(',');
+        regtype.push_str(&subtype_label(subtype)?);
+    }
+    Ok(regtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtypes() {
+        assert_eq!(subtype_label("_printer"), Ok("_printer".to_string()));
+        assert_eq!(subtype_label("printer"), Ok("_printer".to_string()));
+        assert_eq!(subtype_label(""), Err("".to_string()));
+        assert_eq!(subtype_label("_"), Err("_".to_string()));
+        assert!(subtype_label("_a.b").is_err());
+        assert!(subtype_label("_a,b").is_err());
+        assert!(subtype_label(&"a".repeat(63)).is_err());
+        assert!(subtype_label(&"a".repeat(62)).is_ok());
+
+        let subtypes = vec!["_printer".to_string(), "scanner".to_string()];
+        assert_eq!(
+            regtype_with_subtypes("_http._tcp", &subtypes),
+            Ok("_http._tcp,_printer,_scanner".to_string())
+        );
+        assert_eq!(
+            regtype_with_subtypes("_http._tcp", &[]),
+            Ok("_http._tcp".to_string())
+        );
+    }
+}
@@ -0,0 +1,488 @@
+//! TXT record creation & handling, shared by every backend
+//!
+//! Encoding & decoding of the TXT rdata wire format is done here in pure Rust, so it behaves the
+//! same no matter which DNS-SD library is in use.
+
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use thiserror::Error;
+
+#[cfg(feature = "serde")]
+mod serde;
+#[cfg(feature = "serde")]
+pub use self::serde::{from_txt_record, to_txt_record, TXTSerdeError};
+
+/// Maximum length of a single TXT entry (`key=value`) in bytes
+pub(crate) const MAX_ENTRY_LEN: usize = 255;
+
+/// Errors from encoding or decoding TXT record data
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TXTError {
+    /// Keys must be at least one character
+    #[error("TXT key must not be empty")]
+    EmptyKey,
+    /// Keys must be printable US-ASCII & not contain '='
+    #[error("TXT key {0:?} must be printable ASCII without '='")]
+    InvalidKey(String),
+    /// A single `key=value` entry is longer than 255 bytes
+    #[error("TXT entry for key {key:?} is {len} bytes, over the 255 byte limit")]
+    EntryTooLong {
+        /// Key of the oversized entry
+        key: String,
+        /// Encoded length of the entry
+        len: usize,
+    },
+    /// Encoded record is longer than allowed
+    #[error("TXT record is {len} bytes, over the {max} byte limit")]
+    RecordTooLong {
+        /// Encoded length of the record
+        len: usize,
+        /// Limit that was exceeded
+        max: usize,
+    },
+    /// Raw data ended before the length byte said it would
+    #[error("TXT data truncated, entry at offset {0} runs past the end")]
+    Truncated(usize),
+    /// Value can't be sent as is by the backend, i.e. key only or binary values with the Windows
+    /// DNS API, which only takes strings
+    #[error("TXT value for key {0:?} can't be sent as is by this DNS-SD backend")]
+    UnsupportedValue(String),
+}
+
+/// Value of a single TXT record attribute (RFC 6763 section 6.4)
+///
+/// An attribute that is absent from the record entirely is represented by `None` when looking it
+/// up with [`TXTRecord::get`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TXTValue {
+    /// Key without an `=`, a boolean attribute that is simply present
+    KeyOnly,
+    /// Key followed by `=` with no value (`key=`)
+    Empty,
+    /// Key with a value, which may be arbitrary binary data
+    Bytes(Vec<u8>),
+}
+
+impl TXTValue {
+    /// Returns the value's bytes, `None` for a key only attribute
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            TXTValue::KeyOnly => None,
+            TXTValue::Empty => Some(&[]),
+            TXTValue::Bytes(bytes) => Some(bytes),
+        }
+    }
+
+    /// Returns the value as a string if it has one & it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    /// Returns true if this is a key only (boolean) attribute
+    pub fn is_key_only(&self) -> bool {
+        matches!(self, TXTValue::KeyOnly)
+    }
+}
+
+impl From<Vec<u8>> for TXTValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        if bytes.is_empty() {
+            TXTValue::Empty
+        } else {
+            TXTValue::Bytes(bytes)
+        }
+    }
+}
+
+impl From<&[u8]> for TXTValue {
+    fn from(bytes: &[u8]) -> Self {
+        TXTValue::from(bytes.to_vec())
+    }
+}
+
+impl From<String> for TXTValue {
+    fn from(s: String) -> Self {
+        TXTValue::from(s.into_bytes())
+    }
+}
+
+impl From<&str> for TXTValue {
+    fn from(s: &str) -> Self {
+        TXTValue::from(s.as_bytes())
+    }
+}
+
+impl From<&String> for TXTValue {
+    fn from(s: &String) -> Self {
+        TXTValue::from(s.as_str())
+    }
+}
+
+/// Represents a TXT Record for dns-sd, containing 0 or more attributes
+///
+/// Attributes keep the order they were inserted or received in, which is also the order they are
+/// encoded in, as some consumers expect particular keys (such as `txtvers`) first. Keys are matched
+/// case-insensitively as required by RFC 6763, but keep the case they were inserted with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TXTRecord {
+    /// Entries in insertion order
+    entries: Vec<(String, TXTValue)>,
+}
+
+impl TXTRecord {
+    /// Largest size that still fits a single packet along with other records, RFC 6763 section 6.2
+    pub const RECOMMENDED_MAX_LEN: usize = 1300;
+    /// Hard limit on the size of a TXT record
+    pub const MAX_LEN: usize = u16::MAX as usize;
+
+    /// Creates a new empty TXT Record
+    pub fn new() -> TXTRecord {
+        TXTRecord::default()
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))
+    }
+
+    /// Sets a key/value pair, returning the previous value for the key if any
+    ///
+    /// A new key is appended to the end, replacing an existing key keeps its position.
+    pub fn insert<V>(&mut self, key: &str, value: V) -> Option<TXTValue>
+    where
+        V: Into<TXTValue>,
+    {
+        let value = value.into();
+        match self.position(key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.entries.push((key.to_owned(), value));
+                None
+            }
+        }
+    }
+
+    /// Sets a key only (boolean) attribute, returning the previous value for the key if any
+    pub fn insert_flag(&mut self, key: &str) -> Option<TXTValue> {
+        self.insert(key, TXTValue::KeyOnly)
+    }
+
+    /// Removes a key, returning its value if it was present
+    pub fn remove(&mut self, key: &str) -> Option<TXTValue> {
+        self.position(key).map(|i| self.entries.remove(i).1)
+    }
+
+    /// Checks if a key exists
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// Returns the value for a key, or `None` if the key is absent
+    pub fn get(&self, key: &str) -> Option<&TXTValue> {
+        self.position(key).map(|i| &self.entries[i].1)
+    }
+
+    /// Returns the bytes of a key's value, `None` if absent or a key only attribute
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.get(key).and_then(TXTValue::as_bytes)
+    }
+
+    /// Returns a key's value as a string, `None` if absent, key only, or not valid UTF-8
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(TXTValue::as_str)
+    }
+
+    /// Returns the number of keys stored in the TXT Record
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns if TXTRecord is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over all keys & values of the TXT Record in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &TXTValue)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+    }
+
+    /// Iterates over all keys of the TXT Record in order
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(key, _)| key.as_str())
+    }
+
+    /// Returns the size in bytes the TXT Record will have once encoded, without validating it
+    pub fn encoded_len(&self) -> usize {
+        self.iter()
+            .map(|(key, value)| 1 + key.len() + value.as_bytes().map_or(0, |v| 1 + v.len()))
+            .sum()
+    }
+
+    /// Checks that the TXT Record can be encoded, see `to_bytes()` for the rules
+    pub fn validate(&self) -> Result<(), TXTError> {
+        self.to_bytes().map(|_| ())
+    }
+
+    /// Encodes the TXT Record into its wire format, a series of length prefixed `key=value` strings
+    ///
+    /// Keys must be non-empty printable ASCII without '=', each entry at most 255 bytes & the whole
+    /// record at most 65535 bytes. An empty record encodes to no bytes at all, DNS-SD libraries fill
+    /// in the required single empty string themselves.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TXTError> {
+        let mut data = Vec::with_capacity(self.encoded_len());
+        for (key, value) in self.iter() {
+            let entry = encode_entry(key, value)?;
+            data.push(entry.len() as u8);
+            data.extend_from_slice(&entry);
+        }
+        if data.len() > TXTRecord::MAX_LEN {
+            return Err(TXTError::RecordTooLong {
+                len: data.len(),
+                max: TXTRecord::MAX_LEN,
+            });
+        }
+        Ok(data)
+    }
+
+    /// Decodes TXT Record wire format data, keeping the order of entries
+    ///
+    /// Following RFC 6763 section 6, empty strings & strings without a key are ignored, and only
+    /// the first instance of a repeated key is kept.
+    pub fn from_bytes(data: &[u8]) -> Result<TXTRecord, TXTError> {
+        let mut txt = TXTRecord::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let len = data[offset] as usize;
+            let entry = data
+                .get(offset + 1..offset + 1 + len)
+                .ok_or(TXTError::Truncated(offset))?;
+            txt.push_entry(entry);
+            offset += 1 + len;
+        }
+        Ok(txt)
+    }
+
+    /// Adds a single raw `key=value` entry as received from the network, ignoring it if invalid
+    /// or if the key was already seen
+    pub(crate) fn push_entry(&mut self, entry: &[u8]) {
+        let (key, value) = match entry.iter().position(|b| *b == b'=') {
+            Some(i) => (&entry[..i], TXTValue::from(&entry[i + 1..])),
+            None => (entry, TXTValue::KeyOnly),
+        };
+        if key.is_empty() {
+            if !entry.is_empty() {
+                trace!("Discarding TXT entry with empty key");
+            }
+            return;
+        }
+        let key = match std::str::from_utf8(key) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Discarding TXT entry with non UTF-8 key: {}", e);
+                return;
+            }
+        };
+        if self.contains_key(key) {
+            trace!("Discarding repeated TXT key {}", key);
+            return;
+        }
+        self.insert(key, value);
+    }
+}
+
+/// Validates & encodes a single entry without its length prefix
+pub(crate) fn encode_entry(key: &str, value: &TXTValue) -> Result<Vec<u8>, TXTError> {
+    if key.is_empty() {
+        return Err(TXTError::EmptyKey);
+    }
+    if !key.bytes().all(|b| (0x20..=0x7E).contains(&b) && b != b'=') {
+        return Err(TXTError::InvalidKey(key.to_owned()));
+    }
+    let mut entry = key.as_bytes().to_vec();
+    if let Some(bytes) = value.as_bytes() {
+        entry.push(b'=');
+        entry.extend_from_slice(bytes);
+    }
+    if entry.len() > MAX_ENTRY_LEN {
+        return Err(TXTError::EntryTooLong {
+            key: key.to_owned(),
+            len: entry.len(),
+        });
+    }
+    Ok(entry)
+}
+
+impl<K, V> FromIterator<(K, V)> for TXTRecord
+where
+    K: AsRef<str>,
+    V: Into<TXTValue>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut txt = TXTRecord::new();
+        for (key, value) in iter {
+            txt.insert(key.as_ref(), value);
+        }
+        txt
+    }
+}
+
+impl IntoIterator for TXTRecord {
+    type Item = (String, TXTValue);
+    type IntoIter = std::vec::IntoIter<(String, TXTValue)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+/// Builds a record from a map, the resulting order is the map's iteration order so use
+/// `TXTRecord` directly if order matters
+impl From<HashMap<String, String>> for TXTRecord {
+    fn from(hash: HashMap<String, String>) -> Self {
+        hash.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txt_values() {
+        let mut record = TXTRecord::new();
+        record.insert("Status", "open");
+        record.insert_flag("paper");
+        record.insert("empty", "");
+        record.insert("bin", vec![0u8, 0xff]);
+        assert_eq!(record.len(), 4);
+        assert_eq!(record.get_str("status"), Some("open"));
+        assert_eq!(record.get("PAPER"), Some(&TXTValue::KeyOnly));
+        assert_eq!(record.get_bytes("paper"), None);
+        assert_eq!(record.get("empty"), Some(&TXTValue::Empty));
+        assert_eq!(record.get_bytes("empty"), Some(&[][..]));
+        assert_eq!(record.get_bytes("bin"), Some(&[0u8, 0xff][..]));
+        assert_eq!(record.get_str("bin"), None);
+        assert_eq!(record.get("missing"), None);
+        assert!(!record.contains_key("missing"));
+        assert_eq!(
+            record.insert("STATUS", "closed"),
+            Some(TXTValue::from("open"))
+        );
+        assert_eq!(record.len(), 4);
+        assert_eq!(record.remove("Empty"), Some(TXTValue::Empty));
+        assert_eq!(record.len(), 3);
+    }
+
+    #[test]
+    fn txt_encoding() {
+        let mut record = TXTRecord::new();
+        record.insert("test", "value1");
+        assert_eq!(record.to_bytes().unwrap(), b"\x0Btest=value1");
+        record.insert("test", vec![1u8, 2, 3]);
+        assert_eq!(record.to_bytes().unwrap(), b"\x08test=\x01\x02\x03");
+        record.insert("test", "");
+        assert_eq!(record.to_bytes().unwrap(), b"\x05test=");
+        record.insert_flag("test");
+        assert_eq!(record.to_bytes().unwrap(), b"\x04test");
+        assert_eq!(TXTRecord::new().to_bytes().unwrap(), b"");
+    }
+
+    #[test]
+    fn txt_order() {
+        let mut record = TXTRecord::new();
+        record.insert("txtvers", "1");
+        record.insert("zeta", "z");
+        record.insert_flag("alpha");
+        record.insert("ZETA", "zz");
+        assert_eq!(
+            record.keys().collect::<Vec<_>>(),
+            ["txtvers", "zeta", "alpha"]
+        );
+        assert_eq!(
+            record.to_bytes().unwrap(),
+            b"\x09txtvers=1\x07zeta=zz\x05alpha"
+        );
+        record.remove("zeta");
+        record.insert("zeta", "z");
+        assert_eq!(
+            record.keys().collect::<Vec<_>>(),
+            ["txtvers", "alpha", "zeta"]
+        );
+
+        let decoded = TXTRecord::from_bytes(&record.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, record);
+    }
+
+    #[test]
+    fn txt_encoding_errors() {
+        let mut record = TXTRecord::new();
+        record.insert("", "value");
+        assert_eq!(record.to_bytes(), Err(TXTError::EmptyKey));
+
+        let mut record = TXTRecord::new();
+        record.insert("a=b", "value");
+        assert_eq!(record.to_bytes(), Err(TXTError::InvalidKey("a=b".into())));
+
+        let mut record = TXTRecord::new();
+        record.insert("key", vec![b'x'; 252]);
+        assert_eq!(
+            record.to_bytes(),
+            Err(TXTError::EntryTooLong {
+                key: "key".into(),
+                len: 256
+            })
+        );
+        record.insert("key", vec![b'x'; 251]);
+        assert_eq!(record.to_bytes().unwrap().len(), 256);
+    }
+
+    #[test]
+    fn txt_size() {
+        let mut record = TXTRecord::new();
+        assert_eq!(record.encoded_len(), 0);
+        record.insert("a", "1");
+        record.insert_flag("flag");
+        record.insert("empty", "");
+        assert_eq!(record.encoded_len(), record.to_bytes().unwrap().len());
+        assert_eq!(record.encoded_len(), 4 + 5 + 7);
+
+        let record: TXTRecord = (0..300)
+            .map(|i| (format!("key{}", i), vec![b'x'; 240]))
+            .collect();
+        assert!(record.encoded_len() > TXTRecord::MAX_LEN);
+        assert_eq!(
+            record.validate(),
+            Err(TXTError::RecordTooLong {
+                len: record.encoded_len(),
+                max: TXTRecord::MAX_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn txt_decoding() {
+        let record =
+            TXTRecord::from_bytes(b"\x0Btest=value1\x09test2=\x01\x02\x03\x04flag\x06empty=")
+                .unwrap();
+        assert_eq!(record.len(), 4);
+        assert_eq!(record.get_str("test"), Some("value1"));
+        assert_eq!(record.get_bytes("test2"), Some(&[1u8, 2, 3][..]));
+        assert_eq!(record.get("flag"), Some(&TXTValue::KeyOnly));
+        assert_eq!(record.get("empty"), Some(&TXTValue::Empty));
+
+        // empty strings & empty keys are ignored, first instance of a key wins
+        let record = TXTRecord::from_bytes(b"\x00\x04=abc\x03a=1\x03b=2\x03A=3").unwrap();
+        assert_eq!(record.len(), 2);
+        assert_eq!(record.get_str("a"), Some("1"));
+        assert_eq!(record.keys().collect::<Vec<_>>(), ["a", "b"]);
+
+        assert_eq!(
+            TXTRecord::from_bytes(b"\x03a=1\x05a"),
+            Err(TXTError::Truncated(4))
+        );
+    }
+}
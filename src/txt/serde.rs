@@ -0,0 +1,655 @@
+//! Serde support for converting typed structs to & from TXT records
+//!
+//! Each field becomes one TXT attribute. `true` bools become key only attributes and `false` ones
+//! are left out, numbers are written as decimal strings and `None` options are left out.
+//! Because left out attributes are absent when deserializing, `bool` fields that may be `false`
+//! need `#[serde(default)]`.
+
+use super::{TXTError, TXTRecord, TXTValue};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, MapAccess, Visitor};
+use serde::ser::{self, Impossible, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Errors converting between typed data & TXT records
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum TXTSerdeError {
+    /// Error not specific to a single attribute
+    #[error("{0}")]
+    Message(String),
+    /// Error converting the attribute with the given key
+    #[error("TXT key {key:?}: {message}")]
+    Key {
+        /// Key of the attribute that failed
+        key: String,
+        /// Description of the failure
+        message: String,
+    },
+    /// Resulting record isn't valid TXT data
+    #[error("Invalid TXT record: {0}")]
+    InvalidTxtRecord(#[from] TXTError),
+}
+
+impl TXTSerdeError {
+    fn with_key(self, key: &str) -> Self {
+        match self {
+            TXTSerdeError::Message(message) => TXTSerdeError::Key {
+                key: key.to_owned(),
+                message,
+            },
+            e => e,
+        }
+    }
+}
+
+impl ser::Error for TXTSerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        TXTSerdeError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for TXTSerdeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        TXTSerdeError::Message(msg.to_string())
+    }
+}
+
+type Result<T, E = TXTSerdeError> = std::result::Result<T, E>;
+
+/// Serializes a struct (or map) into a TXT record, one attribute per field
+pub fn to_txt_record<T: Serialize + ?Sized>(value: &T) -> Result<TXTRecord> {
+    let mut serializer = RecordSerializer {
+        txt: TXTRecord::new(),
+        key: None,
+    };
+    value.serialize(&mut serializer)?;
+    // catch invalid keys & oversized values now, rather than when registering
+    serializer.txt.to_bytes()?;
+    Ok(serializer.txt)
+}
+
+/// Deserializes a struct (or map) from a TXT record's attributes
+///
+/// Struct fields are matched to keys case-insensitively, like TXT record keys themselves.
+pub fn from_txt_record<T: DeserializeOwned>(txt: &TXTRecord) -> Result<T> {
+    T::deserialize(RecordDeserializer { txt })
+}
+
+fn unsupported<T>(what: &str) -> Result<T> {
+    Err(TXTSerdeError::Message(format!(
+        "{} can't be represented in a TXT record",
+        what
+    )))
+}
+
+struct RecordSerializer {
+    txt: TXTRecord,
+    /// Key waiting for its value when serializing a map
+    key: Option<String>,
+}
+
+impl RecordSerializer {
+    fn field<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<()> {
+        match value
+            .serialize(ValueSerializer)
+            .map_err(|e| e.with_key(key))?
+        {
+            Some(value) => {
+                if self.txt.insert(key, value).is_some() {
+                    return Err(TXTSerdeError::Message("duplicate key".into()).with_key(key));
+                }
+            }
+            None => trace!("Leaving out TXT key {}", key),
+        }
+        Ok(())
+    }
+}
+
+macro_rules! top_level_unsupported {
+    ($($method:ident($($arg:ty),*) -> $ret:ty;)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<$ret> {
+                unsupported("Only a struct or map at the top level")
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for &mut RecordSerializer {
+    type Ok = ();
+    type Error = TXTSerdeError;
+    type SerializeSeq = Impossible<(), TXTSerdeError>;
+    type SerializeTuple = Impossible<(), TXTSerdeError>;
+    type SerializeTupleStruct = Impossible<(), TXTSerdeError>;
+    type SerializeTupleVariant = Impossible<(), TXTSerdeError>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), TXTSerdeError>;
+
+    top_level_unsupported! {
+        serialize_bool(bool) -> ();
+        serialize_i8(i8) -> ();
+        serialize_i16(i16) -> ();
+        serialize_i32(i32) -> ();
+        serialize_i64(i64) -> ();
+        serialize_u8(u8) -> ();
+        serialize_u16(u16) -> ();
+        serialize_u32(u32) -> ();
+        serialize_u64(u64) -> ();
+        serialize_f32(f32) -> ();
+        serialize_f64(f64) -> ();
+        serialize_char(char) -> ();
+        serialize_str(&str) -> ();
+        serialize_bytes(&[u8]) -> ();
+        serialize_none() -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(&'static str) -> ();
+        serialize_unit_variant(&'static str, u32, &'static str) -> ();
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()> {
+        unsupported("Only a struct or map at the top level")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Ok(self)
+    }
+}
+
+impl ser::SerializeStruct for &mut RecordSerializer {
+    type Ok = ();
+    type Error = TXTSerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut RecordSerializer {
+    type Ok = ();
+    type Error = TXTSerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let key = match key.serialize(ValueSerializer)? {
+            Some(TXTValue::Bytes(bytes)) => String::from_utf8(bytes)
+                .map_err(|_| TXTSerdeError::Message("map keys must be UTF-8 strings".into()))?,
+            _ => return unsupported("A map key that isn't a string"),
+        };
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| TXTSerdeError::Message("map value without a key".into()))?;
+        self.field(&key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Serializes a single field, `None` meaning it is left out of the record
+struct ValueSerializer;
+
+macro_rules! serialize_display {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Option<TXTValue>> {
+                Ok(Some(TXTValue::from(v.to_string())))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<TXTValue>;
+    type Error = TXTSerdeError;
+    type SerializeSeq = Impossible<Option<TXTValue>, TXTSerdeError>;
+    type SerializeTuple = Impossible<Option<TXTValue>, TXTSerdeError>;
+    type SerializeTupleStruct = Impossible<Option<TXTValue>, TXTSerdeError>;
+    type SerializeTupleVariant = Impossible<Option<TXTValue>, TXTSerdeError>;
+    type SerializeMap = Impossible<Option<TXTValue>, TXTSerdeError>;
+    type SerializeStruct = Impossible<Option<TXTValue>, TXTSerdeError>;
+    type SerializeStructVariant = Impossible<Option<TXTValue>, TXTSerdeError>;
+
+    serialize_display! {
+        serialize_i8(i8);
+        serialize_i16(i16);
+        serialize_i32(i32);
+        serialize_i64(i64);
+        serialize_i128(i128);
+        serialize_u8(u8);
+        serialize_u16(u16);
+        serialize_u32(u32);
+        serialize_u64(u64);
+        serialize_u128(u128);
+        serialize_f32(f32);
+        serialize_f64(f64);
+        serialize_char(char);
+        serialize_str(&str);
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok> {
+        Ok(if v { Some(TXTValue::KeyOnly) } else { None })
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok> {
+        Ok(Some(TXTValue::from(v)))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok> {
+        Ok(Some(TXTValue::KeyOnly))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok> {
+        unsupported("An enum variant with data")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        unsupported("A sequence")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        unsupported("A tuple")
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        unsupported("A tuple struct")
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        unsupported("An enum variant with data")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        unsupported("A nested map")
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        unsupported("A nested struct")
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        unsupported("An enum variant with data")
+    }
+}
+
+struct RecordDeserializer<'a> {
+    txt: &'a TXTRecord,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for RecordDeserializer<'a> {
+    type Error = TXTSerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(RecordAccess {
+            entries: self.txt.iter(),
+            fields: &[],
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_map(RecordAccess {
+            entries: self.txt.iter(),
+            fields,
+            value: None,
+        })
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct RecordAccess<'a, I> {
+    entries: I,
+    /// Struct field names, to match keys case-insensitively
+    fields: &'static [&'static str],
+    /// Value of the key last returned, along with the key for errors
+    value: Option<(&'a str, &'a TXTValue)>,
+}
+
+impl<'de, 'a, I> MapAccess<'de> for RecordAccess<'a, I>
+where
+    I: Iterator<Item = (&'a str, &'a TXTValue)>,
+{
+    type Error = TXTSerdeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some((key, value));
+                let key = self
+                    .fields
+                    .iter()
+                    .find(|field| field.eq_ignore_ascii_case(key))
+                    .copied()
+                    .unwrap_or(key);
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| TXTSerdeError::Message("value requested before key".into()))?;
+        seed.deserialize(ValueDeserializer { value })
+            .map_err(|e| e.with_key(key))
+    }
+}
+
+struct ValueDeserializer<'a> {
+    value: &'a TXTValue,
+}
+
+impl<'a> ValueDeserializer<'a> {
+    fn str(&self) -> Result<&'a str> {
+        match self.value {
+            TXTValue::KeyOnly => Err(TXTSerdeError::Message(
+                "expected a value, found a key only attribute".into(),
+            )),
+            value => value
+                .as_str()
+                .ok_or_else(|| TXTSerdeError::Message("value isn't valid UTF-8".into())),
+        }
+    }
+
+    fn parse<T>(&self, what: &str) -> Result<T>
+    where
+        T: FromStr,
+    {
+        let s = self.str()?;
+        s.parse()
+            .map_err(|_| TXTSerdeError::Message(format!("expected {}, found {:?}", what, s)))
+    }
+}
+
+macro_rules! deserialize_parse {
+    ($($method:ident => $visit:ident($ty:ty, $what:expr);)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                visitor.$visit(self.parse::<$ty>($what)?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = TXTSerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            TXTValue::KeyOnly => visitor.visit_bool(true),
+            value => match value.as_str() {
+                Some(s) => visitor.visit_str(s),
+                None => visitor.visit_bytes(value.as_bytes().unwrap_or_default()),
+            },
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            TXTValue::KeyOnly => visitor.visit_bool(true),
+            _ => match self.str()? {
+                "true" | "1" => visitor.visit_bool(true),
+                "false" | "0" => visitor.visit_bool(false),
+                s => Err(TXTSerdeError::Message(format!(
+                    "expected a bool, found {:?}",
+                    s
+                ))),
+            },
+        }
+    }
+
+    deserialize_parse! {
+        deserialize_i8 => visit_i8(i8, "an integer");
+        deserialize_i16 => visit_i16(i16, "an integer");
+        deserialize_i32 => visit_i32(i32, "an integer");
+        deserialize_i64 => visit_i64(i64, "an integer");
+        deserialize_i128 => visit_i128(i128, "an integer");
+        deserialize_u8 => visit_u8(u8, "an unsigned integer");
+        deserialize_u16 => visit_u16(u16, "an unsigned integer");
+        deserialize_u32 => visit_u32(u32, "an unsigned integer");
+        deserialize_u64 => visit_u64(u64, "an unsigned integer");
+        deserialize_u128 => visit_u128(u128, "an unsigned integer");
+        deserialize_f32 => visit_f32(f32, "a number");
+        deserialize_f64 => visit_f64(f64, "a number");
+        deserialize_char => visit_char(char, "a single character");
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_str(self.str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value.as_bytes() {
+            Some(bytes) => visitor.visit_bytes(bytes),
+            None => Err(TXTSerdeError::Message(
+                "expected a value, found a key only attribute".into(),
+            )),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        // the attribute being present at all means Some
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let de: de::value::StrDeserializer<TXTSerdeError> = self.str()?.into_deserializer();
+        visitor.visit_enum(de)
+    }
+
+    serde::forward_to_deserialize_any! {
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Mode {
+        Idle,
+        Busy,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Metadata {
+        txtvers: u8,
+        device_id: String,
+        #[serde(default)]
+        secure: bool,
+        #[serde(default)]
+        beta: bool,
+        players: Option<u32>,
+        version: f32,
+        mode: Mode,
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let meta = Metadata {
+            txtvers: 1,
+            device_id: "ab:cd".into(),
+            secure: true,
+            beta: false,
+            players: None,
+            version: 1.5,
+            mode: Mode::Busy,
+        };
+        let txt = to_txt_record(&meta).unwrap();
+        assert_eq!(
+            txt.to_bytes().unwrap(),
+            b"\x09txtvers=1\x0fdevice_id=ab:cd\x06secure\x0bversion=1.5\x09mode=busy"
+        );
+        assert_eq!(from_txt_record::<Metadata>(&txt).unwrap(), meta);
+    }
+
+    #[test]
+    fn serde_keys_and_errors() {
+        let txt: TXTRecord = vec![
+            ("TXTVERS", "1"),
+            ("device_id", "x"),
+            ("version", "2"),
+            ("mode", "idle"),
+            ("players", "many"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            from_txt_record::<Metadata>(&txt),
+            Err(TXTSerdeError::Key {
+                key: "players".into(),
+                message: "expected an unsigned integer, found \"many\"".into()
+            })
+        );
+
+        #[derive(Serialize)]
+        struct Nested {
+            inner: Vec<u8>,
+        }
+        assert!(matches!(
+            to_txt_record(&Nested { inner: vec![1] }),
+            Err(TXTSerdeError::Key { key, .. }) if key == "inner"
+        ));
+        assert!(matches!(
+            to_txt_record(&5u8),
+            Err(TXTSerdeError::Message(_))
+        ));
+    }
+}